@@ -1,9 +1,98 @@
-use ic_cdk::api::time;
-
 use super::constants::*;
+use super::store_lib::Clock;
 
 type Amount = u128;
 
+/// Math Error
+///
+/// Returned by checked calculations instead of trapping
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MathError {
+    /// The result does not fit in the result type
+    Overflow,
+    /// The result would be negative
+    Underflow,
+    /// The divisor is zero
+    DivisionByZero,
+}
+
+/// Rounding
+///
+/// The direction to round the result of a division in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rounding {
+    /// rounds towards zero
+    Down,
+    /// rounds away from zero
+    Up,
+}
+
+/// Mul Div
+///
+/// Calculates x * y / z exactly ,the product is widened to 256 bits so it can not overflow
+///
+/// Returns
+///  - Result :The rounded result or an error if z is zero or the result does not fit in 128 bits
+pub fn _mul_div(x: u128, y: u128, z: u128, rounding: Rounding) -> Result<u128, MathError> {
+    if z == 0 {
+        return Err(MathError::DivisionByZero);
+    }
+
+    let (high, low) = _wide_mul(x, y);
+
+    // the quotient fits in 128 bits only if the high part is less than the divisor
+    if high >= z {
+        return Err(MathError::Overflow);
+    }
+
+    let (quotient, remainder) = if high == 0 {
+        (low / z, low % z)
+    } else {
+        let mut remainder = high;
+        let mut quotient: u128 = 0;
+
+        for bit in (0..128).rev() {
+            let carry = remainder >> 127;
+            remainder = (remainder << 1) | ((low >> bit) & 1);
+            quotient <<= 1;
+
+            if carry == 1 || remainder >= z {
+                remainder = remainder.wrapping_sub(z);
+                quotient |= 1;
+            }
+        }
+
+        (quotient, remainder)
+    };
+
+    if rounding == Rounding::Up && remainder != 0 {
+        return quotient.checked_add(1).ok_or(MathError::Overflow);
+    }
+    return Ok(quotient);
+}
+
+/// Wide Mul
+///
+/// Multiplies two 128 bit integers into a 256 bit integer returned as its (high ,low) 128 bit halves
+fn _wide_mul(x: u128, y: u128) -> (u128, u128) {
+    const MASK: u128 = u64::MAX as u128;
+
+    let (x_high, x_low) = (x >> 64, x & MASK);
+    let (y_high, y_low) = (y >> 64, y & MASK);
+
+    let low_low = x_low * y_low;
+    let low_high = x_low * y_high;
+    let high_low = x_high * y_low;
+    let high_high = x_high * y_high;
+
+    let middle = (low_low >> 64) + (low_high & MASK) + (high_low & MASK);
+
+    let low = (low_low & MASK) | (middle << 64);
+    let high = high_high + (low_high >> 64) + (high_low >> 64) + (middle >> 64);
+
+    return (high, low);
+}
+
 /// Calculate Interest Function
 ///
 /// This function calculates the interest on a leveraged position since when it was filled
@@ -17,11 +106,16 @@ pub enum InterestCalcError {
     InvalidTime,
 }
 
-pub fn _calc_interest(debt: Amount, interest_rate: u32, start_time: u64) -> Amount {
+pub fn _calc_interest<C: Clock>(
+    debt: Amount,
+    interest_rate: u32,
+    start_time: u64,
+    clock: &C,
+) -> Amount {
     let mut fee: Amount = 0;
     let one_hour: u64 = 3600 * ((10u64).pow(9));
     let mut current_hour = start_time;
-    let current_time = time();
+    let current_time = clock.now();
 
     if start_time > current_time {
         return 0;
@@ -52,12 +146,11 @@ pub fn _calc_shares(
     amount_in: Amount,
     init_total_shares: Amount,
     init_liquidity: Amount,
-) -> Amount {
+) -> Result<Amount, MathError> {
     if init_total_shares == 0 {
-        return amount_in;
+        return Ok(amount_in);
     }
-    // unsafe
-    return (amount_in * init_total_shares) / init_liquidity;
+    return _mul_div(amount_in, init_total_shares, init_liquidity, Rounding::Down);
 }
 
 /// Calculate Shares Value
@@ -67,9 +160,8 @@ pub fn _calc_shares_value(
     shares: Amount,
     init_total_shares: Amount,
     init_liquidity: Amount,
-) -> Amount {
-    // unsafe
-    return (shares * init_liquidity) / init_total_shares;
+) -> Result<Amount, MathError> {
+    return _mul_div(shares, init_liquidity, init_total_shares, Rounding::Down);
 }
 
 /// Percentage Functions
@@ -82,3 +174,15 @@ where
 {
     ((T::from(x)) * value) / T::from(100 * _ONE_PERCENT)
 }
+
+/// Checked Percentage
+///
+/// Calculates a percentage of an amount without overflowing
+pub fn _checked_percentage(x: u64, value: Amount) -> Result<Amount, MathError> {
+    _mul_div(
+        Amount::from(x),
+        value,
+        Amount::from(100 * _ONE_PERCENT),
+        Rounding::Down,
+    )
+}
@@ -0,0 +1,294 @@
+use super::constants::{_ONE_BASIS_POINT, _ONE_PERCENT};
+use super::store_lib::{BitmapsStore, TicksStore};
+use super::tick_lib::_int_and_dec;
+use crate::types::TickState;
+
+type Tick = u64;
+type Amount = u128;
+
+/// Get Book Depth
+///
+/// Walks the integrals bitmaps from the best offer tick outward and collects the static liquidity
+/// at each initialised tick on one side of the book
+///
+/// Params
+///  - Buy : true to collect sell offers (walking upward) or false to collect buy offers (walking downward)
+///  - Best Offer Tick : the tick to start from ,i.e the lowest sell offer or the highest buy offer
+///  - Levels : the maximum number of levels to return
+///  - Group Size : the number of ticks merged into a single level ,buy offers are grouped down and sell offers
+///  are grouped up to the bucket boundary
+///
+/// Returns
+///  - A vector of (level tick ,liquidity) ordered from the best offer outward
+///
+/// Note
+///  - Liquidity is in the quote asset for buy offers and in the base asset for sell offers
+pub fn _get_book_depth<M: BitmapsStore, T: TicksStore>(
+    buy: bool,
+    best_offer_tick: Tick,
+    levels: usize,
+    group_size: Tick,
+    integrals_bitmaps: &M,
+    ticks_details: &T,
+) -> Vec<(Tick, Amount)> {
+    let mut book_levels: Vec<(Tick, Amount)> = Vec::new();
+
+    if best_offer_tick == 0 || levels == 0 {
+        return book_levels;
+    }
+
+    let group_size = group_size.max(1);
+
+    let level_tick = |tick: Tick| -> Tick {
+        if buy {
+            tick.div_ceil(group_size) * group_size
+        } else {
+            (tick / group_size) * group_size
+        }
+    };
+
+    for tick in _book_ticks(buy, best_offer_tick, integrals_bitmaps) {
+        let Some(tick_details) = ticks_details.get(&tick) else {
+            continue;
+        };
+
+        // this checks the tick is on the requested side of the book
+        let right_side = match tick_details.tick_state {
+            TickState::SELL => buy,
+            TickState::BUY => !buy,
+        };
+
+        let liquidity = tick_details.liq_bounds._liquidity_within();
+
+        if !right_side || liquidity == 0 {
+            continue;
+        }
+
+        let current_level_tick = level_tick(tick);
+
+        match book_levels.last_mut() {
+            Some((last_level_tick, last_liquidity)) if *last_level_tick == current_level_tick => {
+                *last_liquidity += liquidity;
+            }
+            _ => {
+                if book_levels.len() == levels {
+                    return book_levels;
+                }
+                book_levels.push((current_level_tick, liquidity));
+            }
+        }
+    }
+
+    book_levels
+}
+
+/// Book Ticks
+///
+/// Returns all possibly initialised ticks starting from a tick ,walking upward for buy or downward otherwise
+fn _book_ticks<'a, M: BitmapsStore>(
+    buy: bool,
+    from_tick: Tick,
+    integrals_bitmaps: &'a M,
+) -> Box<dyn Iterator<Item = Tick> + 'a> {
+    let (from_integral, _) = _int_and_dec(from_tick);
+
+    if buy {
+        Box::new(
+            integrals_bitmaps
+                .integrals_from(from_integral)
+                .flat_map(|(integral, bitmap)| _initialised_ticks(integral, bitmap))
+                .filter(move |tick| *tick >= from_tick),
+        )
+    } else {
+        Box::new(
+            integrals_bitmaps
+                .integrals_down_from(from_integral)
+                .flat_map(|(integral, bitmap)| {
+                    _initialised_ticks(integral, bitmap).into_iter().rev()
+                })
+                .filter(move |tick| *tick <= from_tick),
+        )
+    }
+}
+
+/// Initialised Ticks
+///
+/// Returns all possibly initialised ticks within an integral in ascending order
+///
+/// Note
+///  - The tick zero of an integral is never flipped in the bitmap (see bitmap_lib) so it is always included
+fn _initialised_ticks(integral: u64, bitmap: u128) -> Vec<Tick> {
+    (0..100u64)
+        .filter(|bit_position| *bit_position == 0 || bitmap & (1u128 << (99 - bit_position)) != 0)
+        .map(|bit_position| (integral * _ONE_PERCENT) + (bit_position * _ONE_BASIS_POINT))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::corelib::bitmap_lib::_flip_bit;
+    use crate::types::{LiquidityBoundary, TickDetails};
+
+    use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+    use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+
+    type Memory = VirtualMemory<DefaultMemoryImpl>;
+    type MB = StableBTreeMap<u64, u128, Memory>;
+    type TD = StableBTreeMap<u64, TickDetails, Memory>;
+
+    fn _stores() -> (MB, TD) {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+        (
+            StableBTreeMap::init(memory_manager.get(MemoryId::new(0))),
+            StableBTreeMap::init(memory_manager.get(MemoryId::new(1))),
+        )
+    }
+
+    fn _add_offer(
+        integrals_bitmaps: &mut MB,
+        ticks_details: &mut TD,
+        tick: Tick,
+        tick_state: TickState,
+        liquidity: Amount,
+    ) {
+        let (integral, bit_position) = _int_and_dec(tick);
+        let bitmap = integrals_bitmaps.get(&integral).unwrap_or(0);
+        integrals_bitmaps.insert(integral, _flip_bit(bitmap, bit_position));
+
+        let mut liq_bounds = LiquidityBoundary::default();
+        liq_bounds._add_liquidity(liquidity);
+        ticks_details.insert(
+            tick,
+            TickDetails {
+                tick_state,
+                liq_bounds,
+                created_timestamp: 0,
+            },
+        );
+    }
+
+    fn _book() -> (MB, TD) {
+        let (mut integrals_bitmaps, mut ticks_details) = _stores();
+
+        _add_offer(
+            &mut integrals_bitmaps,
+            &mut ticks_details,
+            20_001_000,
+            TickState::SELL,
+            10,
+        );
+        _add_offer(
+            &mut integrals_bitmaps,
+            &mut ticks_details,
+            20_002_000,
+            TickState::SELL,
+            20,
+        );
+        _add_offer(
+            &mut integrals_bitmaps,
+            &mut ticks_details,
+            20_005_000,
+            TickState::SELL,
+            30,
+        );
+
+        _add_offer(
+            &mut integrals_bitmaps,
+            &mut ticks_details,
+            19_999_000,
+            TickState::BUY,
+            5,
+        );
+        _add_offer(
+            &mut integrals_bitmaps,
+            &mut ticks_details,
+            19_998_000,
+            TickState::BUY,
+            7,
+        );
+        _add_offer(
+            &mut integrals_bitmaps,
+            &mut ticks_details,
+            19_995_000,
+            TickState::BUY,
+            9,
+        );
+        _add_offer(
+            &mut integrals_bitmaps,
+            &mut ticks_details,
+            19_899_000,
+            TickState::BUY,
+            4,
+        );
+
+        (integrals_bitmaps, ticks_details)
+    }
+
+    #[test]
+    fn test_sell_offers_each_tick_is_a_level() {
+        let (integrals_bitmaps, ticks_details) = _book();
+
+        let depth = _get_book_depth(true, 20_001_000, 10, 1, &integrals_bitmaps, &ticks_details);
+
+        assert_eq!(
+            depth,
+            vec![(20_001_000, 10), (20_002_000, 20), (20_005_000, 30)]
+        );
+    }
+
+    #[test]
+    fn test_sell_offers_are_grouped_up() {
+        let (integrals_bitmaps, ticks_details) = _book();
+
+        let depth = _get_book_depth(
+            true,
+            20_001_000,
+            10,
+            2_000,
+            &integrals_bitmaps,
+            &ticks_details,
+        );
+
+        assert_eq!(depth, vec![(20_002_000, 30), (20_006_000, 30)]);
+    }
+
+    #[test]
+    fn test_buy_offers_are_grouped_down_across_integrals() {
+        let (integrals_bitmaps, ticks_details) = _book();
+
+        let depth = _get_book_depth(
+            false,
+            19_999_000,
+            10,
+            2_000,
+            &integrals_bitmaps,
+            &ticks_details,
+        );
+
+        assert_eq!(
+            depth,
+            vec![(19_998_000, 12), (19_994_000, 9), (19_898_000, 4)]
+        );
+    }
+
+    #[test]
+    fn test_levels_limit_keeps_the_last_group_whole() {
+        let (integrals_bitmaps, ticks_details) = _book();
+
+        let depth = _get_book_depth(
+            true,
+            20_001_000,
+            1,
+            2_000,
+            &integrals_bitmaps,
+            &ticks_details,
+        );
+
+        assert_eq!(depth, vec![(20_002_000, 30)]);
+
+        assert!(
+            _get_book_depth(true, 20_001_000, 0, 1, &integrals_bitmaps, &ticks_details).is_empty()
+        );
+    }
+}
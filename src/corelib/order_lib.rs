@@ -2,46 +2,50 @@ use serde::{Deserialize, Serialize};
 
 use super::bitmap_lib::_flip_bit;
 
-use super::price_lib::_equivalent;
+use super::calc_lib::MathError;
+use super::price_lib::{_checked_equivalent, Rounding};
+use super::store_lib::{BitmapsStore, Clock, TicksStore};
 use super::tick_lib::_int_and_dec;
 
 use candid::CandidType;
 
 use crate::types::{TickDetails, TickState};
 
-use ic_stable_structures::{memory_manager::VirtualMemory, DefaultMemoryImpl, StableBTreeMap};
-
-type Memory = VirtualMemory<DefaultMemoryImpl>;
 type Time = u64;
 type Tick = u64;
 type Amount = u128;
-type MB = StableBTreeMap<u64, u128, Memory>;
-type TD = StableBTreeMap<u64, TickDetails, Memory>;
 
 /// Order Trait for different OrderTypes
 pub trait Order {
     fn _opening_update(&mut self, ref_tick_details: &mut TickDetails);
-    fn _closing_update(&self, ref_tick_details: &mut TickDetails) -> (Amount, Amount);
+    fn _closing_update(
+        &self,
+        ref_tick_details: &mut TickDetails,
+    ) -> Result<(Amount, Amount), MathError>;
 }
 
 ///OpenOrderParams for creating orders
 
-pub struct OpenOrderParams<'a> {
+pub struct OpenOrderParams<'a, M: BitmapsStore, T: TicksStore, C: Clock> {
     /// Multiplier BitMaps
     ///
     ///A HashMap of  multipliers (percentiles) to their respective bitmap
-    pub integrals_bitmaps: &'a mut MB,
+    pub integrals_bitmaps: &'a mut M,
     ///Ticks Details
     ///
     ///A HashMap of tick to their tick_details
-    pub ticks_details: &'a mut TD,
+    pub ticks_details: &'a mut T,
     /// Order
     ///
     /// A mutable refrence to any generic type that implements the Order trait  determing which order type is being opened
     pub order: &'a mut LimitOrder,
+    /// Clock
+    ///
+    /// The clock used to timestamp newly created ticks
+    pub clock: &'a C,
 }
 
-impl<'a> OpenOrderParams<'a> {
+impl<'a, M: BitmapsStore, T: TicksStore, C: Clock> OpenOrderParams<'a, M, T, C> {
     /// Open Order function
     ///
     /// creates an order at a particular tick
@@ -57,7 +61,7 @@ impl<'a> OpenOrderParams<'a> {
 
                     self.integrals_bitmaps.insert(integral, flipped_bitmap);
 
-                    TickDetails::new()
+                    TickDetails::new(self.clock.now())
                 });
 
         self.order._opening_update(&mut tick_details);
@@ -67,7 +71,7 @@ impl<'a> OpenOrderParams<'a> {
 
 ///CloseOrderParams for closing order
 
-pub struct CloseOrderParams<'a> {
+pub struct CloseOrderParams<'a, M: BitmapsStore, T: TicksStore> {
     ///Order
     ///
     /// An immutable reference  to a  generic type order that implements the Order trait,
@@ -77,30 +81,38 @@ pub struct CloseOrderParams<'a> {
     /// Multipliers Bitmaps
     ///
     ///A HashMap of  multipliers (percentiles) to their respective bitmap
-    pub integrals_bitmaps: &'a mut MB,
+    pub integrals_bitmaps: &'a mut M,
     ///Ticks Details
     ///
     ///A HashMap of tick to their tick_details
-    pub ticks_details: &'a mut TD,
+    pub ticks_details: &'a mut T,
 }
 
-impl<'a> CloseOrderParams<'a> {
+impl<'a, M: BitmapsStore, T: TicksStore> CloseOrderParams<'a, M, T> {
     /// Close_order function
     ///
     /// Returns a tuple
     ///
     /// Amount0 : Amount of token expected from the order
     /// Amount1 : Amount remaining in the order
-    pub fn close_order(&mut self) -> (Amount, Amount) {
+    ///
+    /// Note
+    ///  - The ticks details and bitmaps are only modified if the amounts could be calculated
+    pub fn close_order(&mut self) -> Result<(Amount, Amount), MathError> {
         let Some(mut tick_details) = self.ticks_details.get(&self.order.ref_tick) else {
             // if tick details does not exist means all trade order  that currently references that tick
             //   has been filled
-            return (
-                _equivalent(self.order.order_size, self.order.ref_tick, self.order.buy),
+            return Ok((
+                _checked_equivalent(
+                    self.order.order_size,
+                    self.order.ref_tick,
+                    self.order.buy,
+                    Rounding::Down,
+                )?,
                 0,
-            );
+            ));
         };
-        let (amount0, amount1) = self.order._closing_update(&mut tick_details);
+        let (amount0, amount1) = self.order._closing_update(&mut tick_details)?;
 
         if tick_details.liq_bounds._liquidity_within() == 0 {
             self.ticks_details.remove(&self.order.ref_tick);
@@ -115,7 +127,78 @@ impl<'a> CloseOrderParams<'a> {
         } else {
             self.ticks_details.insert(self.order.ref_tick, tick_details);
         }
-        return (amount0, amount1);
+        return Ok((amount0, amount1));
+    }
+}
+
+///AmendOrderParams for reducing an order in place
+
+pub struct AmendOrderParams<'a, T: TicksStore> {
+    /// Order
+    ///
+    /// A mutable reference to the order being amended
+    pub order: &'a mut LimitOrder,
+    ///Ticks Details
+    ///
+    ///A HashMap of tick to their tick_details
+    pub ticks_details: &'a mut T,
+}
+
+impl<'a, T: TicksStore> AmendOrderParams<'a, T> {
+    /// Reduce Order function
+    ///
+    /// reduces the size of an unfilled order without changing its position in the reference tick queue
+    ///
+    /// Returns
+    ///  - Reduced :true if the order was reduced ,false if the order has been partially or fully filled
+    ///  or the new order size is not less than the current order size
+    pub fn reduce_order(&mut self, new_order_size: Amount) -> bool {
+        if new_order_size == 0 || new_order_size >= self.order.order_size {
+            return false;
+        }
+
+        let Some(mut tick_details) = self.ticks_details.get(&self.order.ref_tick) else {
+            return false;
+        };
+
+        if !self.order._unfilled_at(&tick_details) {
+            return false;
+        }
+
+        // removing liquidity shifts the lower bound of the tick and of every order on it equally
+        // so the queue position of the order is kept
+        tick_details._remove_liquidity(self.order.order_size - new_order_size);
+        self.ticks_details.insert(self.order.ref_tick, tick_details);
+
+        self.order.order_size = new_order_size;
+
+        return true;
+    }
+}
+
+/// Is Order Unfilled
+///
+/// Returns true if no part of the order has been filled
+pub fn _is_order_unfilled<T: TicksStore>(order: &LimitOrder, ticks_details: &T) -> bool {
+    match ticks_details.get(&order.ref_tick) {
+        Some(tick_details) => order._unfilled_at(&tick_details),
+        None => false,
+    }
+}
+
+/// Order Amounts
+///
+/// Returns the amounts closing the order would return ,without modifying the ticks details (see close_order)
+pub fn _order_amounts<T: TicksStore>(
+    order: &LimitOrder,
+    ticks_details: &T,
+) -> Result<(Amount, Amount), MathError> {
+    match ticks_details.get(&order.ref_tick) {
+        Some(mut tick_details) => order._closing_update(&mut tick_details),
+        None => Ok((
+            _checked_equivalent(order.order_size, order.ref_tick, order.buy, Rounding::Down)?,
+            0,
+        )),
     }
 }
 
@@ -157,6 +240,22 @@ impl LimitOrder {
             init_tick_timestamp: 0,
         };
     }
+
+    /// Unfilled At
+    ///
+    /// checks that the reference tick has not been crossed since the order was placed
+    /// and that no liquidity ahead of or within the order has been utilised
+    fn _unfilled_at(&self, tick_details: &TickDetails) -> bool {
+        if self.init_tick_timestamp < tick_details.created_timestamp {
+            return false;
+        }
+
+        let order_lower_bound = self.init_lower_bound
+            + tick_details.liq_bounds.lifetime_removed_liquidity
+            - self.init_removed_liquidity;
+
+        return tick_details.liq_bounds.lower_bound <= order_lower_bound;
+    }
 }
 
 impl Order for LimitOrder {
@@ -189,14 +288,19 @@ impl Order for LimitOrder {
     /// - Amount Out :This returns the amount of the particular asset expected from the order
     /// i.e base asset(perp asset) for a buy order and quote asset (collateral asset) for a sell order
     /// - Amount Remaining :This  returns the amount  not filled in the order  
-    fn _closing_update(&self, tick_details: &mut TickDetails) -> (Amount, Amount) {
+    fn _closing_update(
+        &self,
+        tick_details: &mut TickDetails,
+    ) -> Result<(Amount, Amount), MathError> {
         //  let tick_price = _tick_to_price(self.ref_tick);
-        let equivalent =
-            |amount: Amount| -> Amount { _equivalent(amount, self.ref_tick, self.buy) };
+        // amounts paid out to the order owner are rounded down
+        let equivalent = |amount: Amount| -> Result<Amount, MathError> {
+            _checked_equivalent(amount, self.ref_tick, self.buy, Rounding::Down)
+        };
 
         // this means order has been filled since tick has been closed before
         if self.init_tick_timestamp < tick_details.created_timestamp {
-            return (equivalent(self.order_size), 0);
+            return Ok((equivalent(self.order_size)?, 0));
         }
 
         let (tick_lower_bound, order_lower_bound) = (
@@ -213,194 +317,17 @@ impl Order for LimitOrder {
                 } else {
                     // order partially filled
                     (
-                        equivalent(tick_lower_bound - order_lower_bound),
+                        equivalent(tick_lower_bound - order_lower_bound)?,
                         (order_lower_bound + self.order_size) - tick_lower_bound,
                     )
                 }
             } else {
                 // order fully filled
-                (equivalent(self.order_size), 0)
+                (equivalent(self.order_size)?, 0)
             };
 
         tick_details._remove_liquidity(amount_remaining);
 
-        return (amount_out, amount_remaining);
+        return Ok((amount_out, amount_remaining));
     }
 }
-
-// #[cfg(test)]
-// mod unit_test_order_lib {
-
-//     use super::*;
-
-//     use crate::types::{Amount, Tick, TickDetails};
-//     use std::cell::RefCell;
-//     use std::collections::HashMap;
-
-//     thread_local! {
-//         static MULTIPLIERS_BITMAPS:RefCell<HashMap<u64,u128>> = RefCell::new(HashMap::new());
-
-//         static TICKS_DETAILS :RefCell<HashMap<Tick,TickDetails>> = RefCell::new(HashMap::new());
-//     }
-
-//     #[test]
-//     fn test_place_order() {
-//         let mut order1 = LimitOrder::new(10000000, 1000, true);
-//         _open_order(&mut order1);
-//         let tick_details = _get_tick_details(1000);
-
-//         assert_eq!(order1.init_lower_bound, 0);
-
-//         assert_eq!(tick_details.liq_bounds_token1.lifetime_removed_liquidity, 0);
-//         assert_eq!(
-//             tick_details.liq_bounds_token1.upper_bound,
-//             order1.order_size,
-//         );
-//         //
-
-//         // Second Order
-//         let mut order2 = LimitOrder::new(1000000, 1000, true);
-//         _open_order(&mut order2);
-
-//         let tick_details = _get_tick_details(1000);
-
-//         assert_eq!(order2.init_lower_bound, order1.order_size);
-
-//         assert_eq!(
-//             tick_details.liq_bounds_token1.upper_bound,
-//             order1.order_size + order2.order_size,
-//         )
-//     }
-
-//     #[test]
-
-//     fn test_open_and_close_order() {
-//         let mut order1 = LimitOrder::new(10000000, 1000, true);
-
-//         //Open order
-//         {
-//             _open_order(&mut order1);
-//             let tick_details = _get_tick_details(1000);
-//             assert_eq!(order1.init_lower_bound, 0);
-
-//             assert_eq!(tick_details.liq_bounds_token1.lifetime_removed_liquidity, 0);
-//             assert_eq!(
-//                 tick_details.liq_bounds_token1.upper_bound,
-//                 order1.order_size,
-//             );
-//         }
-//         //Close order
-//         {
-//             let (amount_out, amount_remaining) = _close_order(&order1);
-
-//             assert_eq!(amount_out, 0);
-
-//             assert_eq!(amount_remaining, order1.order_size);
-//         }
-//     }
-
-//     #[test]
-//     fn test_open_close_different_orders() {
-//         let mut order1 = LimitOrder::new(10000000, 1000, true);
-//         {
-//             _open_order(&mut order1);
-//             let tick_details = _get_tick_details(1000);
-
-//             assert_eq!(order1.init_lower_bound, 0);
-
-//             assert_eq!(tick_details.liq_bounds_token1.lifetime_removed_liquidity, 0);
-//             assert_eq!(
-//                 tick_details.liq_bounds_token1.upper_bound,
-//                 order1.order_size,
-//             );
-//         }
-//         //
-
-//         // Second Order
-//         let mut order2 = LimitOrder::new(1000000, 1000, true);
-//         {
-//             _open_order(&mut order2);
-
-//             let tick_details = _get_tick_details(1000);
-
-//             assert_eq!(order2.init_lower_bound, order1.order_size);
-
-//             assert_eq!(
-//                 tick_details.liq_bounds_token1.upper_bound,
-//                 order1.order_size + order2.order_size,
-//             );
-//         }
-
-//         let init_tick_details = _get_tick_details(1000);
-
-//         // Remove Order
-//         {
-//             let (amount_out, amount_remaining) = _close_order(&order1);
-
-//             assert_eq!(amount_out, 0);
-
-//             assert_eq!(amount_remaining, order1.order_size);
-//         }
-
-//         let tick_details = _get_tick_details(1000);
-//         //assert lifetime _removed liquidity is
-//         assert_eq!(
-//             tick_details.liq_bounds_token1.lifetime_removed_liquidity,
-//             init_tick_details
-//                 .liq_bounds_token1
-//                 .lifetime_removed_liquidity
-//                 + order1.order_size
-//         );
-
-//         assert_eq!(
-//             tick_details.liq_bounds_token1._liquidity_within(),
-//             order2.order_size
-//         );
-//     }
-
-//     ///
-//     ///
-//     ///
-//     fn _get_tick_details(tick: Tick) -> TickDetails {
-//         TICKS_DETAILS
-//             .with(|ref_tick_details| return ref_tick_details.borrow().get(&tick).unwrap().clone())
-//     }
-
-//     //
-
-//     fn _open_order(order: &mut LimitOrder) {
-//         TICKS_DETAILS.with(|ref_ticks_details| {
-//             let ticks_details = &mut *ref_ticks_details.borrow_mut();
-//             MULTIPLIERS_BITMAPS.with(|ref_multiplier_bitmaps| {
-//                 let multipliers_bitmaps = &mut *ref_multiplier_bitmaps.borrow_mut();
-
-//                 let mut open_order_params = OpenOrderParams {
-//                     order,
-//                     integrals_bitmaps: multipliers_bitmaps,
-//                     ticks_details,
-//                 };
-//                 open_order_params.open_order();
-//             })
-//         });
-//     }
-
-//     ///
-//     ///
-//     ///
-//     ///
-//     fn _close_order(order: &LimitOrder) -> (Amount, Amount) {
-//         TICKS_DETAILS.with(|ref_ticks_details| {
-//             let ticks_details = &mut *ref_ticks_details.borrow_mut();
-//             MULTIPLIERS_BITMAPS.with(|ref_multiplier_bitmaps| {
-//                 let multipliers_bitmaps = &mut *ref_multiplier_bitmaps.borrow_mut();
-
-//                 let mut close_order_params = CloseOrderParams {
-//                     order,
-//                     integrals_bitmaps: multipliers_bitmaps,
-//                     ticks_details,
-//                 };
-//                 close_order_params.close_order()
-//             })
-//         })
-//     }
-// }
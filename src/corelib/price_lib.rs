@@ -1,17 +1,119 @@
+use super::calc_lib::{_mul_div, MathError};
 use super::constants::_PRICE_FACTOR;
 
+pub use super::calc_lib::Rounding;
+
 type Amount = u128;
 
-pub fn _equivalent(amount: Amount, price: u64, buy: bool) -> Amount {
-    // unsafe
+/// Equivalent
+///
+/// Converts an amount at a price ,rounding down (see _checked_equivalent)
+pub fn _equivalent(amount: Amount, price: u64, buy: bool) -> Result<Amount, MathError> {
+    _checked_equivalent(amount, price, buy, Rounding::Down)
+}
+
+/// Checked Equivalent
+///
+/// Converts an amount at a price using exact integer math
+///
+/// Params
+///  - Amount :The amount to convert
+///  - Price :The price (tick) to convert at
+///  - Buy :true to convert quote asset to base asset or false to convert base asset to quote asset
+///  - Rounding :The direction to round the result in
+///
+/// Note
+///  - Amounts paid to the protocol should be rounded up and amounts paid out rounded down
+pub fn _checked_equivalent(
+    amount: Amount,
+    price: u64,
+    buy: bool,
+    rounding: Rounding,
+) -> Result<Amount, MathError> {
     if buy {
-        let result = (amount * _PRICE_FACTOR) / price as u128;
-        if amount == _equivalent(result, price, false) {
-            return result;
-        } else {
-            return (((amount as f64) / price as f64) * _PRICE_FACTOR as f64) as u128;
-        }
+        _mul_div(amount, _PRICE_FACTOR, price as u128, rounding)
     } else {
-        return (amount * price as u128) / _PRICE_FACTOR;
+        _mul_div(amount, price as u128, _PRICE_FACTOR, rounding)
+    }
+}
+
+/// Median
+///
+/// Returns the median of a set of prices ,the average of the two middle prices if the set has an even length
+/// or none if the set is empty
+pub fn _median(mut prices: Vec<u64>) -> Option<u64> {
+    if prices.is_empty() {
+        return None;
+    }
+
+    prices.sort_unstable();
+
+    let middle = prices.len() / 2;
+
+    if prices.len() % 2 == 0 {
+        return Some(((prices[middle - 1] as u128 + prices[middle] as u128) / 2) as u64);
+    }
+    return Some(prices[middle]);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // a price of 3 ,i.e 300%
+    const TICK: u64 = 30_000_000;
+
+    #[test]
+    fn test_exact_conversion_is_not_rounded() {
+        assert_eq!(_checked_equivalent(30, TICK, true, Rounding::Down), Ok(10));
+        assert_eq!(_checked_equivalent(30, TICK, true, Rounding::Up), Ok(10));
+
+        assert_eq!(_checked_equivalent(10, TICK, false, Rounding::Down), Ok(30));
+        assert_eq!(_checked_equivalent(10, TICK, false, Rounding::Up), Ok(30));
+    }
+
+    #[test]
+    fn test_buy_conversion_rounding() {
+        // 10 of the quote asset buys 3.33 of the base asset
+        assert_eq!(_checked_equivalent(10, TICK, true, Rounding::Down), Ok(3));
+        assert_eq!(_checked_equivalent(10, TICK, true, Rounding::Up), Ok(4));
+    }
+
+    #[test]
+    fn test_sell_conversion_rounding() {
+        // 10 of the base asset sells for 30.0000001 of the quote asset
+        assert_eq!(
+            _checked_equivalent(10, TICK + 1, false, Rounding::Down),
+            Ok(30)
+        );
+        assert_eq!(
+            _checked_equivalent(10, TICK + 1, false, Rounding::Up),
+            Ok(31)
+        );
+    }
+
+    #[test]
+    fn test_equivalent_rounds_down() {
+        assert_eq!(_equivalent(10, TICK, true), Ok(3));
+        assert_eq!(_equivalent(10, TICK + 1, false), Ok(30));
+        assert_eq!(_equivalent(0, TICK, true), Ok(0));
+    }
+
+    #[test]
+    fn test_conversion_overflow() {
+        // a price below one buys more of the base asset than the quote asset paid
+        assert_eq!(
+            _checked_equivalent(Amount::MAX, TICK / 30, true, Rounding::Down),
+            Err(MathError::Overflow)
+        );
+        assert_eq!(
+            _checked_equivalent(Amount::MAX, TICK + 1, false, Rounding::Up),
+            Err(MathError::Overflow)
+        );
+    }
+
+    #[test]
+    fn test_buy_conversion_at_zero_tick() {
+        assert_eq!(_equivalent(10, 0, true), Err(MathError::DivisionByZero));
     }
 }
@@ -0,0 +1,135 @@
+use crate::types::TickDetails;
+
+use ic_stable_structures::{Memory, StableBTreeMap};
+
+use std::collections::BTreeMap;
+
+type Time = u64;
+type Tick = u64;
+
+/// Ticks Store
+///
+/// A map of ticks to their tick details
+///
+/// Note
+///  - Implemented for the stable btree map used by the canister and for an in memory btree map
+///  so the matching engine can run outside a canister
+pub trait TicksStore {
+    fn get(&self, tick: &Tick) -> Option<TickDetails>;
+    fn insert(&mut self, tick: Tick, tick_details: TickDetails);
+    fn remove(&mut self, tick: &Tick);
+    fn contains_key(&self, tick: &Tick) -> bool;
+}
+
+/// Bitmaps Store
+///
+/// A map of integrals to their bitmaps (see bitmap_lib)
+pub trait BitmapsStore {
+    fn get(&self, integral: &u64) -> Option<u128>;
+    fn insert(&mut self, integral: u64, bitmap: u128);
+    fn remove(&mut self, integral: &u64);
+    /// returns all integrals with a bitmap starting from an integral in ascending order
+    fn integrals_from(&self, from_integral: u64) -> Box<dyn Iterator<Item = (u64, u128)> + '_>;
+    /// returns all integrals with a bitmap up to and including an integral in descending order
+    fn integrals_down_from(&self, from_integral: u64)
+        -> Box<dyn Iterator<Item = (u64, u128)> + '_>;
+}
+
+/// Clock
+///
+/// Provides the current time in nanoseconds
+pub trait Clock {
+    fn now(&self) -> Time;
+}
+
+/// System Clock
+///
+/// Clock for running outside a canister ,returns the system time since the unix epoch
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Time {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_nanos() as Time)
+    }
+}
+
+impl<M: Memory> TicksStore for StableBTreeMap<Tick, TickDetails, M> {
+    fn get(&self, tick: &Tick) -> Option<TickDetails> {
+        StableBTreeMap::get(self, tick)
+    }
+    fn insert(&mut self, tick: Tick, tick_details: TickDetails) {
+        StableBTreeMap::insert(self, tick, tick_details);
+    }
+    fn remove(&mut self, tick: &Tick) {
+        StableBTreeMap::remove(self, tick);
+    }
+    fn contains_key(&self, tick: &Tick) -> bool {
+        StableBTreeMap::contains_key(self, tick)
+    }
+}
+
+impl TicksStore for BTreeMap<Tick, TickDetails> {
+    fn get(&self, tick: &Tick) -> Option<TickDetails> {
+        BTreeMap::get(self, tick).copied()
+    }
+    fn insert(&mut self, tick: Tick, tick_details: TickDetails) {
+        BTreeMap::insert(self, tick, tick_details);
+    }
+    fn remove(&mut self, tick: &Tick) {
+        BTreeMap::remove(self, tick);
+    }
+    fn contains_key(&self, tick: &Tick) -> bool {
+        BTreeMap::contains_key(self, tick)
+    }
+}
+
+impl<M: Memory> BitmapsStore for StableBTreeMap<u64, u128, M> {
+    fn get(&self, integral: &u64) -> Option<u128> {
+        StableBTreeMap::get(self, integral)
+    }
+    fn insert(&mut self, integral: u64, bitmap: u128) {
+        StableBTreeMap::insert(self, integral, bitmap);
+    }
+    fn remove(&mut self, integral: &u64) {
+        StableBTreeMap::remove(self, integral);
+    }
+    fn integrals_from(&self, from_integral: u64) -> Box<dyn Iterator<Item = (u64, u128)> + '_> {
+        Box::new(self.range(from_integral..))
+    }
+    fn integrals_down_from(
+        &self,
+        from_integral: u64,
+    ) -> Box<dyn Iterator<Item = (u64, u128)> + '_> {
+        Box::new(self.range(..=from_integral).rev())
+    }
+}
+
+impl BitmapsStore for BTreeMap<u64, u128> {
+    fn get(&self, integral: &u64) -> Option<u128> {
+        BTreeMap::get(self, integral).copied()
+    }
+    fn insert(&mut self, integral: u64, bitmap: u128) {
+        BTreeMap::insert(self, integral, bitmap);
+    }
+    fn remove(&mut self, integral: &u64) {
+        BTreeMap::remove(self, integral);
+    }
+    fn integrals_from(&self, from_integral: u64) -> Box<dyn Iterator<Item = (u64, u128)> + '_> {
+        Box::new(
+            self.range(from_integral..)
+                .map(|(integral, bitmap)| (*integral, *bitmap)),
+        )
+    }
+    fn integrals_down_from(
+        &self,
+        from_integral: u64,
+    ) -> Box<dyn Iterator<Item = (u64, u128)> + '_> {
+        Box::new(
+            self.range(..=from_integral)
+                .rev()
+                .map(|(integral, bitmap)| (*integral, *bitmap)),
+        )
+    }
+}
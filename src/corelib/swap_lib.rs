@@ -1,26 +1,23 @@
 use super::bitmap_lib::{_flip_bit, _next_initialised_tick};
-use super::price_lib::_equivalent;
+use super::calc_lib::MathError;
+use super::price_lib::{_checked_equivalent, Rounding};
+use super::store_lib::{BitmapsStore, TicksStore};
 use super::tick_lib::*;
 use crate::types::{TickDetails, TickState};
 
-use ic_stable_structures::{memory_manager::VirtualMemory, DefaultMemoryImpl, StableBTreeMap};
-
-type Memory = VirtualMemory<DefaultMemoryImpl>;
 type Tick = u64;
 type Amount = u128;
-type MB = StableBTreeMap<u64, u128, Memory>;
-type TD = StableBTreeMap<u64, TickDetails, Memory>;
 
 /// Get Best Offer
 ///
 ///Gets the best offer for either selling or buying
 
-pub fn _get_best_offer<'a>(
+pub fn _get_best_offer<'a, M: BitmapsStore, T: TicksStore>(
     buy: bool,
     current_tick: Tick,
     stopping_tick: Tick,
-    integrals_bitmaps: &'a mut MB,
-    ticks_details: &'a mut TD,
+    integrals_bitmaps: &'a mut M,
+    ticks_details: &'a mut T,
 ) -> Option<Tick> {
     let mut resulting_tick = 0;
     let mut loop_current_tick = current_tick;
@@ -72,14 +69,74 @@ pub fn _get_best_offer<'a>(
     }
 }
 
-struct SwapTickConstants {
+/// Tick Fill
+///
+/// The result of filling a swap against the static liquidity at a single tick
+struct TickFill {
     tick: Tick,
-    order_size: Amount,
+    /// the amount of the swap consumed at tick
+    amount_in: Amount,
+    /// the amount received at tick
+    amount_out: Amount,
+    /// the tick details after the fill
+    tick_details: TickDetails,
+    /// true if all static liquidity at tick is cleared
+    boundary_closed: bool,
+}
+
+/// Level Fill
+///
+/// The part of a swap filled at a single tick
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LevelFill {
+    /// the tick filled at
+    pub tick: Tick,
+    /// the amount of the swap consumed at tick ,in quote for a buy and in base for a sell
+    pub amount_in: Amount,
+    /// the amount received at tick ,in base for a buy and in quote for a sell
+    pub amount_out: Amount,
+}
+
+/// Swap Result
+///
+/// The result of a swap executed against the book
+#[derive(Debug)]
+pub struct SwapResult {
+    /// the amount of token gotten from the swap
+    pub amount_out: Amount,
+    /// the amount of asset remaining due to swap not being completely filled before stopping tick
+    pub amount_remaining: Amount,
+    /// the tick at which either asset was fully swapped or tick before stopping tick was exceeded
+    pub resulting_tick: Tick,
+    /// the ticks whose static liquidity was cleared
+    pub crossed_ticks: Vec<Tick>,
+    /// the fill at each tick in the order they were filled
+    pub fills: Vec<LevelFill>,
+}
+
+/// Swap Outcome
+///
+/// The fills of a swap computed without modifying the book
+struct SwapOutcome {
+    amount_out: Amount,
+    amount_remaining: Amount,
+    resulting_tick: Tick,
+    fills: Vec<TickFill>,
+}
+
+impl SwapOutcome {
+    fn crossed_ticks(&self) -> Vec<Tick> {
+        self.fills
+            .iter()
+            .filter(|fill| fill.boundary_closed)
+            .map(|fill| fill.tick)
+            .collect()
+    }
 }
 
 /// SwapParams for initiating  a swap
 /// utilsed for opening position at market price
-pub struct SwapParams<'a> {
+pub struct SwapParams<'a, M: BitmapsStore, T: TicksStore> {
     /// Swap Direction
     ///
     /// true if buying or false if selling
@@ -105,223 +162,252 @@ pub struct SwapParams<'a> {
     /// Multiplier BitMaps
     ///
     /// HashMap  of integrals to their bitmaps
-    pub integrals_bitmaps: &'a mut MB,
+    pub integrals_bitmaps: &'a mut M,
     /// Ticks Details
     ///
     /// HashMasp  of ticks to their  respective tick_details
-    pub ticks_details: &'a mut TD,
+    pub ticks_details: &'a mut T,
 }
 
-impl<'a> SwapParams<'a> {
+impl<'a, M: BitmapsStore, T: TicksStore> SwapParams<'a, M, T> {
     /// Swap Function
     ///
     /// Swap is executed as a loop starting at the current tick till stopping tick is reached is exceeded
     ///
     /// Returns
-    ///  - AmountOut :The amount of token gotten from the swap
-    ///  - AmountRemaining :The amount of asset remaining dues to swap not being completely filled before stopping tick
-    ///  - Current or Resulting Tick : This corresponds to the tick at which either asset was fully swapped
-    /// or tick before stopping tick was exceeded
+    ///  - Swap Result :The amount out ,the amount remaining ,the resulting tick ,the crossed ticks and the fill at each tick
+    ///    (see SwapResult)
     ///
-    ///  - Crossed Ticks :The Total ticks that were crossed
-    pub fn _swap(&mut self) -> (Amount, Amount, Tick, Vec<Tick>) {
-        let mut amount_out = 0;
-
-        let mut amount_remaining = self.order_size;
-
-        let mut resulting_tick = self.init_tick;
-
-        let mut crossed_ticks: Vec<Tick> = Vec::new();
+    /// Note
+    ///  - All fills are computed before the book is modified ,so on an error the book is left unchanged
+    pub fn _swap(&mut self) -> Result<SwapResult, MathError> {
+        let outcome = _swap_outcome(
+            self.buy,
+            self.init_tick,
+            self.stopping_tick,
+            self.order_size,
+            self.integrals_bitmaps,
+            self.ticks_details,
+        )?;
+
+        let crossed_ticks = outcome.crossed_ticks();
+
+        let mut fills = Vec::with_capacity(outcome.fills.len());
+
+        for fill in outcome.fills {
+            fills.push(LevelFill {
+                tick: fill.tick,
+                amount_in: fill.amount_in,
+                amount_out: fill.amount_out,
+            });
+
+            if !fill.boundary_closed {
+                self.ticks_details.insert(fill.tick, fill.tick_details);
+                continue;
+            }
 
-        let mut loop_current_tick = self.init_tick;
+            // if static liquidity was exhausted at that tick
+            self.ticks_details.remove(&fill.tick);
 
-        while !(_exceeded_stopping_tick(loop_current_tick, self.stopping_tick, self.buy)) {
-            let (integral, bit_position) = _int_and_dec(loop_current_tick);
+            let (integral, bit_position) = _int_and_dec(fill.tick);
 
             let Some(bitmap) = self.integrals_bitmaps.get(&integral) else {
-                // if integral has no bitmap means that means  no tick within that integral   is
-                //initialised
-
-                // calculates the  next_default tick (See bitmap_lib)
-                // if next default tick exceeds stopping tick
-                //breaks else
-                // updates current tick to the next default tick
-
-                let next_default_tick = _next_default_tick(integral, self.buy);
-                if _exceeded_stopping_tick(next_default_tick, self.stopping_tick, self.buy) {
-                    break;
-                };
-
-                loop_current_tick = next_default_tick;
-                //stops currrent iteration,starts the next at the next default tick
                 continue;
             };
 
-            let tick_params = SwapTickConstants {
-                order_size: amount_remaining,
-                tick: loop_current_tick,
-            };
-
-            let (value_out, boundary_closed);
+            let flipped_bitmap = _flip_bit(bitmap, bit_position);
 
-            if self.buy {
-                (value_out, amount_remaining, boundary_closed) = self._buy_at_tick(tick_params);
+            let tick_zero = _tick_zero(integral);
+            // if flipping bitmap results in zero and tick zero(see bitmap_lib) is not contained in ticks_details hashmap
+            //delete btimap
+            if flipped_bitmap == 0 && !self.ticks_details.contains_key(&tick_zero) {
+                self.integrals_bitmaps.remove(&integral);
             } else {
-                (value_out, amount_remaining, boundary_closed) = self._sell_at_tick(tick_params);
-            }
-
-            if value_out > 0 {
-                amount_out += value_out;
-
-                resulting_tick = loop_current_tick;
-
-                // if static liquidity was exhausted at that tick
-
-                if boundary_closed {
-                    self.ticks_details.remove(&loop_current_tick);
-                    //add ticks to list of crossed ticks
-                    crossed_ticks.push(loop_current_tick);
-
-                    let flipped_bitmap = _flip_bit(bitmap, bit_position);
-
-                    let tick_zero = _tick_zero(integral);
-                    // if flipping bitmap results in zero and tick zero(see bitmap_lib) is not contained in ticks_details hashmap
-                    //delete btimap
-                    if flipped_bitmap == 0 && !self.ticks_details.contains_key(&tick_zero) {
-                        self.integrals_bitmaps.remove(&integral);
-                    } else {
-                        // insert flipped bitmap
-                        self.integrals_bitmaps.insert(integral, flipped_bitmap);
-                    };
-                }
-
-                if amount_remaining == 0 {
-                    break;
-                }
-            }
-
-            //println!()
-            let next_initialised_tick =
-                _next_initialised_tick(bitmap, bit_position, integral, self.buy);
-
-            loop_current_tick = next_initialised_tick;
+                // insert flipped bitmap
+                self.integrals_bitmaps.insert(integral, flipped_bitmap);
+            };
         }
-        // if swap could not happen ,current tick remains unchanged and can only be changed manually
 
-        return (amount_out, amount_remaining, resulting_tick, crossed_ticks);
+        Ok(SwapResult {
+            amount_out: outcome.amount_out,
+            amount_remaining: outcome.amount_remaining,
+            resulting_tick: outcome.resulting_tick,
+            crossed_ticks,
+            fills,
+        })
     }
+}
 
-    /// buy at tick function
-    ///
-    /// Performs a swap at a particular tick
-    ///
-    /// Returns
-    /// - AmountOut :The amount resulting from the swap for a buy order at that tick
-    /// - AmountRemaining :The amount remaining from swapping at that tick ,
-    ///  this is  zero if the swap is completedly fully at tick
-    /// - Cleared : true if all liquidity at tick  was cleared
-    /// - Boundary Closed : true if all static liquidity at tick (see TickDetails and LiquidityBoundary) is cleared
-    fn _buy_at_tick(&mut self, params: SwapTickConstants) -> (Amount, Amount, bool) {
-        let mut amount_out = 0;
-
-        let mut amount_remaining = params.order_size;
-
-        let boundary_closed;
-
-        // let tick_price = _tick_to_price(params.tick);
-
-        let equivalent =
-            |amount: Amount, buy: bool| -> Amount { _equivalent(amount, params.tick, buy) };
+/// Simulate Swap
+///
+/// Runs the same traversal as SwapParams::_swap without mutating the ticks details or the integrals bitmaps
+///
+/// Returns
+///  - AmountOut :The amount of token that would be gotten from the swap
+///  - AmountRemaining :The amount of asset that would remain unswapped before the stopping tick
+///  - Resulting Tick :The tick the swap would end at
+///  - Crossed Ticks :The ticks whose static liquidity would be cleared
+pub fn _simulate_swap<M: BitmapsStore, T: TicksStore>(
+    buy: bool,
+    init_tick: Tick,
+    stopping_tick: Tick,
+    order_size: Amount,
+    integrals_bitmaps: &M,
+    ticks_details: &T,
+) -> Result<(Amount, Amount, Tick, Vec<Tick>), MathError> {
+    let outcome = _swap_outcome(
+        buy,
+        init_tick,
+        stopping_tick,
+        order_size,
+        integrals_bitmaps,
+        ticks_details,
+    )?;
+
+    Ok((
+        outcome.amount_out,
+        outcome.amount_remaining,
+        outcome.resulting_tick,
+        outcome.crossed_ticks(),
+    ))
+}
 
-        let Some(mut tick_details) = self.ticks_details.get(&params.tick) else {
-            return (amount_out, amount_remaining, false);
-        };
+/// Swap Outcome
+///
+/// Walks the book from the init tick till the stopping tick is exceeded or the order is filled
+/// and computes the fill at each tick without modifying the book
+fn _swap_outcome<M: BitmapsStore, T: TicksStore>(
+    buy: bool,
+    init_tick: Tick,
+    stopping_tick: Tick,
+    order_size: Amount,
+    integrals_bitmaps: &M,
+    ticks_details: &T,
+) -> Result<SwapOutcome, MathError> {
+    let mut outcome = SwapOutcome {
+        amount_out: 0,
+        amount_remaining: order_size,
+        resulting_tick: init_tick,
+        fills: Vec::new(),
+    };
+
+    let mut loop_current_tick = init_tick;
 
-        if let TickState::BUY = tick_details.tick_state {
-            return (amount_out, amount_remaining, false);
-        }
+    while !(_exceeded_stopping_tick(loop_current_tick, stopping_tick, buy)) {
+        let (integral, bit_position) = _int_and_dec(loop_current_tick);
 
-        let init_tick_liq = tick_details.liq_bounds._liquidity_within();
+        let Some(bitmap) = integrals_bitmaps.get(&integral) else {
+            // if integral has no bitmap means that means  no tick within that integral   is
+            //initialised
 
-        //value of all_liquidity in token1
-        let init_liq_equivalent = equivalent(init_tick_liq, false);
+            // calculates the  next_default tick (See bitmap_lib)
+            // if next default tick exceeds stopping tick
+            //breaks else
+            // updates current tick to the next default tick
 
-        if init_liq_equivalent <= self.order_size {
-            // all liquidity has been exhausted
-            amount_out = init_tick_liq;
+            let next_default_tick = _next_default_tick(integral, buy);
+            if _exceeded_stopping_tick(next_default_tick, stopping_tick, buy) {
+                break;
+            };
 
-            amount_remaining -= init_liq_equivalent;
-        } else {
-            //liquidity remains
-            amount_out = equivalent(self.order_size, true);
+            loop_current_tick = next_default_tick;
+            //stops currrent iteration,starts the next at the next default tick
+            continue;
+        };
 
-            amount_remaining = 0;
+        if let Some(mut tick_details) = ticks_details.get(&loop_current_tick) {
+            if let Some((value_out, amount_remaining, boundary_closed)) = _tick_fill(
+                buy,
+                loop_current_tick,
+                outcome.amount_remaining,
+                &mut tick_details,
+            )? {
+                if value_out > 0 {
+                    outcome.amount_out = outcome
+                        .amount_out
+                        .checked_add(value_out)
+                        .ok_or(MathError::Overflow)?;
+
+                    outcome.fills.push(TickFill {
+                        tick: loop_current_tick,
+                        amount_in: outcome.amount_remaining - amount_remaining,
+                        amount_out: value_out,
+                        tick_details,
+                        boundary_closed,
+                    });
+
+                    outcome.amount_remaining = amount_remaining;
+
+                    outcome.resulting_tick = loop_current_tick;
+
+                    if amount_remaining == 0 {
+                        break;
+                    }
+                }
+            }
         }
 
-        tick_details.liq_bounds._reduce_boundary(amount_out);
-
-        boundary_closed = tick_details.liq_bounds._liquidity_within() == 0;
-
-        self.ticks_details.insert(params.tick, tick_details);
+        let next_initialised_tick = _next_initialised_tick(bitmap, bit_position, integral, buy);
 
-        return (amount_out, amount_remaining, boundary_closed);
+        loop_current_tick = next_initialised_tick;
     }
+    // if swap could not happen ,current tick remains unchanged and can only be changed manually
 
-    /// Sell at tick function
-    ///
-    /// Performs a swap at a particular tick
-    ///
-    /// Returns
-    /// - AmountOut :The amount resulting from the swap for a sell order at that tick
-    /// - AmountRemaining :The amount remaining from swapping at that tick ,
-    ///  this is  zero if the swap is completedly fully at tick
-    /// - Cleared : true if all liquidity at tick  was cleared
-    /// - Boundary Closed : true if all static liquidity at tick (see TickDetails and LiquidityBoundary) is cleared
-
-    fn _sell_at_tick(&mut self, params: SwapTickConstants) -> (Amount, Amount, bool) {
-        let mut amount_out = 0;
-
-        let mut amount_remaining = params.order_size;
-
-        let boundary_closed;
-
-        //  let tick_price = _tick_to_price(params.tick);
-
-        let equivalent =
-            |amount: Amount, buy: bool| -> Amount { _equivalent(amount, params.tick, buy) };
-
-        // tick details
-        let Some(mut tick_details) = self.ticks_details.get(&params.tick) else {
-            return (amount_out, amount_remaining, false);
-        };
-
-        if let TickState::SELL = tick_details.tick_state {
-            return (amount_out, amount_remaining, false);
-        }
-
-        let init_tick_liq = tick_details.liq_bounds._liquidity_within();
-
-        let init_liq_equivalent = equivalent(init_tick_liq, true);
+    Ok(outcome)
+}
 
-        if init_liq_equivalent <= self.order_size {
-            amount_out = init_tick_liq;
+/// Tick Fill
+///
+/// Computes the fill of a swap against the static liquidity at a tick and reduces the tick's liquidity boundary
+///
+/// Params
+///  - Buy :The swap direction
+///  - Tick :The tick being filled at
+///  - Amount Remaining :The amount of the swap not yet filled
+///  - Tick Details :The details of the tick ,updated in place
+///
+/// Returns
+///  - Fill :The amount out ,the amount remaining and true if all static liquidity at tick is cleared
+///    or none if the tick holds liquidity for the same direction as the swap
+fn _tick_fill(
+    buy: bool,
+    tick: Tick,
+    amount_remaining: Amount,
+    tick_details: &mut TickDetails,
+) -> Result<Option<(Amount, Amount, bool)>, MathError> {
+    // this checks that the swap is in the right direction
+    // i.e if buying then the tick state must be sell and vice versa
+    match tick_details.tick_state {
+        TickState::SELL if !buy => return Ok(None),
+        TickState::BUY if buy => return Ok(None),
+        _ => {}
+    }
 
-            amount_remaining -= init_liq_equivalent;
-        } else {
-            //liquidity remains
-            amount_out = equivalent(self.order_size, false);
+    let init_tick_liq = tick_details
+        .liq_bounds
+        .upper_bound
+        .checked_sub(tick_details.liq_bounds.lower_bound)
+        .ok_or(MathError::Underflow)?;
 
-            amount_remaining = 0;
-        }
+    // value of all liquidity in the asset being swapped ,rounded up as it is paid by the swap
+    let init_liq_equivalent = _checked_equivalent(init_tick_liq, tick, !buy, Rounding::Up)?;
 
-        tick_details.liq_bounds._reduce_boundary(amount_out);
+    let (amount_out, amount_remaining) = if init_liq_equivalent <= amount_remaining {
+        // all liquidity has been exhausted
+        (init_tick_liq, amount_remaining - init_liq_equivalent)
+    } else {
+        //liquidity remains ,rounded down as it is paid out to the swap
+        (
+            _checked_equivalent(amount_remaining, tick, buy, Rounding::Down)?,
+            0,
+        )
+    };
 
-        boundary_closed = tick_details.liq_bounds._liquidity_within() == 0;
+    tick_details.liq_bounds._reduce_boundary(amount_out);
 
-        self.ticks_details.insert(params.tick, tick_details);
+    let boundary_closed = tick_details.liq_bounds._liquidity_within() == 0;
 
-        return (amount_out, amount_remaining, boundary_closed);
-    }
+    Ok(Some((amount_out, amount_remaining, boundary_closed)))
 }
 
 // #[test]
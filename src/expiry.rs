@@ -0,0 +1,114 @@
+//! # Limit Position Expiry Functions
+//!
+//! These functions cancel limit positions that are not filled before their expiry time
+//!
+//! Operation
+//!   - the expiry of each limit position is stored in LIMIT POSITIONS EXPIRY under the position's account
+//!     and indexed by time in EXPIRING LIMIT POSITIONS
+//!   - while any expiry is set ,a timer cancels every expired limit position as if closed by its owner
+//!   - an index entry whose expiry no longer matches the account's expiry is stale and is dropped
+
+use ic_cdk_timers::TimerId;
+
+use crate::{
+    _close_limit_position, _get_account_position, _get_expiry_timer, _get_market_details,
+    _set_expiry_timer, PositionOrderType, Vault, _LIMIT_POSITIONS_EXPIRY_CHECK_INTERVAL,
+    _MAX_EXPIRED_POSITIONS_PER_CHECK, EXPIRING_LIMIT_POSITIONS, LIMIT_POSITIONS_EXPIRY,
+};
+
+use std::time::Duration;
+
+type Time = u64;
+type Subaccount = [u8; 32];
+
+/// Set Limit Position Expiry
+///
+/// Sets or clears (if none) the expiry of an account's limit position
+pub(crate) fn _set_limit_position_expiry(account: Subaccount, expiry: Option<Time>) {
+    let previous_expiry = LIMIT_POSITIONS_EXPIRY.with_borrow_mut(|reference| match expiry {
+        Some(expiry) => reference.insert(account, expiry),
+        None => reference.remove(&account),
+    });
+
+    if let Some(previous_expiry) = previous_expiry {
+        EXPIRING_LIMIT_POSITIONS
+            .with_borrow_mut(|reference| reference.remove(&(previous_expiry, account)));
+    }
+
+    if let Some(expiry) = expiry {
+        EXPIRING_LIMIT_POSITIONS
+            .with_borrow_mut(|reference| reference.insert((expiry, account), ()));
+        _schedule_limit_positions_expiry_check();
+    }
+}
+
+/// Schedule Limit Positions Expiry Check
+///
+/// Starts the expiry check timer if any expiry is set and the timer is not already running
+pub(crate) fn _schedule_limit_positions_expiry_check() {
+    let has_expiries = EXPIRING_LIMIT_POSITIONS.with_borrow(|reference| !reference.is_empty());
+
+    if has_expiries && _get_expiry_timer() == TimerId::default() {
+        let timer_id = ic_cdk_timers::set_timer_interval(
+            Duration::from_nanos(_LIMIT_POSITIONS_EXPIRY_CHECK_INTERVAL),
+            || {
+                _cancel_expired_limit_positions();
+            },
+        );
+
+        _set_expiry_timer(timer_id);
+    }
+}
+
+/// Cancel Expired Limit Positions
+///
+/// Closes every limit position whose expiry time has passed ,at most _MAX_EXPIRED_POSITIONS_PER_CHECK per call
+///
+/// Note
+///  - A partially filled limit position is converted into a market position of the filled amount (see closeLimitPosition)
+fn _cancel_expired_limit_positions() {
+    let now = ic_cdk::api::time();
+
+    let expired: Vec<(Time, Subaccount)> = EXPIRING_LIMIT_POSITIONS.with_borrow(|reference| {
+        reference
+            .range(..=(now, [u8::MAX; 32]))
+            .take(_MAX_EXPIRED_POSITIONS_PER_CHECK)
+            .map(|(key, _)| key)
+            .collect()
+    });
+
+    let market_details = _get_market_details();
+
+    let vault = Vault::init(market_details.vault_id);
+
+    for (expiry, account) in expired {
+        EXPIRING_LIMIT_POSITIONS.with_borrow_mut(|reference| reference.remove(&(expiry, account)));
+
+        let current_expiry =
+            LIMIT_POSITIONS_EXPIRY.with_borrow(|reference| reference.get(&account));
+
+        if current_expiry != Some(expiry) {
+            continue;
+        }
+
+        LIMIT_POSITIONS_EXPIRY.with_borrow_mut(|reference| reference.remove(&account));
+
+        let Some(mut position) = _get_account_position(&account) else {
+            continue;
+        };
+
+        if let PositionOrderType::Market = position.order_type {
+            continue;
+        }
+
+        // a position whose order amounts can not be calculated is left open
+        let _ = _close_limit_position(position.owner, position.account_index, &mut position, vault);
+    }
+
+    let has_expiries = EXPIRING_LIMIT_POSITIONS.with_borrow(|reference| !reference.is_empty());
+
+    if !has_expiries {
+        ic_cdk_timers::clear_timer(_get_expiry_timer());
+        _set_expiry_timer(TimerId::default());
+    }
+}
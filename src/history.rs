@@ -0,0 +1,232 @@
+//! # Trade History Functions
+//!
+//! These functions record the fills of every swap in the trade history and aggregate them into candles
+//!
+//! Operation
+//!   - each fill is stored in TRADES under a sequential trade id and indexed by the taker's account in ACCOUNTS TRADES
+//!   - the fills of a swap are added to the current candle of every candle interval in CANDLES
+
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+use crate::types::MarketDetails;
+use crate::{_get_market_details, ACCOUNTS_TRADES, CANDLES, ONE_DAY, ONE_HOUR, ONE_MINUTE, TRADES};
+
+use std::borrow::Cow;
+
+use ic_stable_structures::{storable::Bound, Storable};
+
+type Time = u64;
+type Amount = u128;
+type Tick = u64;
+type Subaccount = [u8; 32];
+type TradeId = u64;
+
+/// Record Trade
+///
+/// Appends the fill of a swap at a single tick to the trade history and indexes it under the taker's account
+///
+/// Params
+///  - Taker :The account that executed the swap
+///  - Buy :true if the taker bought the base asset
+///  - Tick :The tick filled at (compressed)
+///  - Amount In :The amount swapped at tick ,in quote for a buy and in base for a sell
+///  - Amount Out :The amount received at tick ,in base for a buy and in quote for a sell
+///
+/// Returns
+///  - Trade :The recorded trade
+pub(crate) fn _record_trade(
+    taker: Subaccount,
+    buy: bool,
+    tick: Tick,
+    amount_in: Amount,
+    amount_out: Amount,
+) -> Trade {
+    let MarketDetails { tick_spacing, .. } = _get_market_details();
+
+    let (base_amount, quote_amount) = if buy {
+        (amount_out, amount_in)
+    } else {
+        (amount_in, amount_out)
+    };
+
+    let trade_id = TRADES.with_borrow(|reference| reference.len());
+
+    let trade = Trade {
+        trade_id,
+        taker,
+        buy,
+        tick: tick * tick_spacing,
+        base_amount,
+        quote_amount,
+        timestamp: ic_cdk::api::time(),
+    };
+
+    TRADES.with_borrow_mut(|reference| reference.insert(trade_id, trade));
+    ACCOUNTS_TRADES.with_borrow_mut(|reference| reference.insert((taker, trade_id), ()));
+
+    trade
+}
+
+/// Update Candles
+///
+/// Adds the per tick trades of a swap to the current candle of every candle interval in the order they were filled ,
+/// opening a new candle if a trade is the first within that interval
+///
+/// Note
+///  - the candle of each interval is read and written once per swap
+pub(crate) fn _update_candles(trades: &[Trade]) {
+    let Some(first_trade) = trades.first() else {
+        return;
+    };
+
+    CANDLES.with_borrow_mut(|reference| {
+        for interval in CandleInterval::_all() {
+            let key = (
+                interval._duration(),
+                interval._open_time(first_trade.timestamp),
+            );
+
+            let mut candle = reference.get(&key).unwrap_or(Candle {
+                open_time: key.1,
+                open: first_trade.tick,
+                high: first_trade.tick,
+                low: first_trade.tick,
+                close: first_trade.tick,
+                base_volume: 0,
+                quote_volume: 0,
+                trades_count: 0,
+            });
+
+            for trade in trades {
+                candle.high = candle.high.max(trade.tick);
+                candle.low = candle.low.min(trade.tick);
+                candle.close = trade.tick;
+                candle.base_volume += trade.base_amount;
+                candle.quote_volume += trade.quote_amount;
+                candle.trades_count += 1;
+            }
+
+            reference.insert(key, candle);
+        }
+    });
+}
+
+/// Trade
+///
+/// A fill executed by a swap ,recorded in the trade history
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, Copy)]
+pub(crate) struct Trade {
+    /// The id of the trade ,trade ids are sequential
+    trade_id: TradeId,
+    /// The account that executed the swap
+    taker: Subaccount,
+    /// true if the taker bought the base asset
+    buy: bool,
+    /// The tick of the fill (uncompressed ,i.e tick * tick_spacing)
+    pub(crate) tick: Tick,
+    /// The amount of base asset exchanged
+    base_amount: Amount,
+    /// The amount of quote asset exchanged
+    quote_amount: Amount,
+    /// timestamp when the trade was executed
+    timestamp: Time,
+}
+
+impl Storable for Trade {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 120,
+        is_fixed_size: false,
+    };
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        bincode::deserialize(bytes.as_ref()).expect("Failed to deserialize Trade")
+    }
+
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let serialized = bincode::serialize(self).expect("Failed to serialize Trade");
+        Cow::Owned(serialized)
+    }
+}
+
+/// Candle Interval
+///
+/// The intervals candles are aggregated over
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub(crate) enum CandleInterval {
+    OneMinute,
+    FiveMinutes,
+    OneHour,
+    OneDay,
+}
+
+impl CandleInterval {
+    fn _all() -> [CandleInterval; 4] {
+        return [
+            CandleInterval::OneMinute,
+            CandleInterval::FiveMinutes,
+            CandleInterval::OneHour,
+            CandleInterval::OneDay,
+        ];
+    }
+
+    /// Duration
+    ///
+    /// Returns the length of the interval in nanoseconds
+    pub(crate) fn _duration(&self) -> Time {
+        match self {
+            CandleInterval::OneMinute => ONE_MINUTE,
+            CandleInterval::FiveMinutes => 5 * ONE_MINUTE,
+            CandleInterval::OneHour => ONE_HOUR,
+            CandleInterval::OneDay => ONE_DAY,
+        }
+    }
+
+    /// Open Time
+    ///
+    /// Returns the open time of the candle a timestamp falls within
+    fn _open_time(&self, timestamp: Time) -> Time {
+        let duration = self._duration();
+        return (timestamp / duration) * duration;
+    }
+}
+
+/// Candle
+///
+/// The OHLCV summary of the trades executed within a candle interval
+///
+/// Note
+///  - Prices are in ticks
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, Copy)]
+pub(crate) struct Candle {
+    /// The start of the interval
+    open_time: Time,
+    /// The tick of the first trade
+    open: Tick,
+    /// The highest tick traded
+    high: Tick,
+    /// The lowest tick traded
+    low: Tick,
+    /// The tick of the last trade
+    close: Tick,
+    /// The amount of base asset traded
+    base_volume: Amount,
+    /// The amount of quote asset traded
+    quote_volume: Amount,
+    /// The number of trades
+    trades_count: u64,
+}
+
+impl Storable for Candle {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 120,
+        is_fixed_size: false,
+    };
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        bincode::deserialize(bytes.as_ref()).expect("Failed to deserialize Candle")
+    }
+
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let serialized = bincode::serialize(self).expect("Failed to serialize Candle");
+        Cow::Owned(serialized)
+    }
+}
@@ -0,0 +1,111 @@
+//! # Insurance Fund Functions
+//!
+//! These functions manage the insurance fund that covers the bad debt of liquidated positions
+//!
+//! Operation
+//!   - a share of every trading fee and of the liquidation fee of every liquidated position is added to INSURANCE FUND
+//!   - a shortfall left by a liquidated position is drawn from the fund and recorded in INSURANCE FUND DRAWS
+
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+use crate::{INSURANCE_FUND, INSURANCE_FUND_DRAWS};
+
+use std::borrow::Cow;
+
+use ic_stable_structures::{storable::Bound, Storable};
+
+type Time = u64;
+type Amount = u128;
+
+/// Add To Insurance Fund
+///
+/// Adds a share of a fee or penalty to the insurance fund
+pub(crate) fn _add_to_insurance_fund(amount: Amount) {
+    if amount == 0 {
+        return;
+    }
+    INSURANCE_FUND.with_borrow_mut(|reference| {
+        let balance = *reference.get();
+        reference.set(balance + amount).unwrap();
+    });
+}
+
+/// Draw From Insurance Fund
+///
+/// Covers as much of a shortfall as the insurance fund balance allows and records the draw
+///
+/// Params
+///  - Owner :The owner of the position with the shortfall
+///  - Account Index :The account index of the position
+///  - Shortfall :The amount of debt that can not be repaid from the position
+///
+/// Returns
+///  - Amount Covered :The amount drawn from the insurance fund
+pub(crate) fn _draw_from_insurance_fund(
+    owner: Principal,
+    account_index: u8,
+    shortfall: Amount,
+) -> Amount {
+    if shortfall == 0 {
+        return 0;
+    }
+
+    let balance = _get_insurance_fund_balance();
+
+    let amount_covered = balance.min(shortfall);
+
+    INSURANCE_FUND.with_borrow_mut(|reference| reference.set(balance - amount_covered).unwrap());
+
+    INSURANCE_FUND_DRAWS.with_borrow_mut(|reference| {
+        let draw_id = reference.len();
+        reference.insert(
+            draw_id,
+            InsuranceFundDraw {
+                owner,
+                account_index,
+                shortfall,
+                amount_covered,
+                timestamp: ic_cdk::api::time(),
+            },
+        );
+    });
+
+    return amount_covered;
+}
+
+pub(crate) fn _get_insurance_fund_balance() -> Amount {
+    INSURANCE_FUND.with_borrow(|reference| *reference.get())
+}
+
+/// Insurance Fund Draw
+///
+/// A draw from the insurance fund to cover the shortfall of a liquidated position
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, Copy)]
+pub(crate) struct InsuranceFundDraw {
+    /// The owner of the liquidated position
+    owner: Principal,
+    /// The account index of the liquidated position
+    account_index: u8,
+    /// The debt that could not be repaid from the position
+    shortfall: Amount,
+    /// The amount covered by the insurance fund ,less than the shortfall if the fund was depleted
+    amount_covered: Amount,
+    /// timestamp when the draw was made
+    timestamp: Time,
+}
+
+impl Storable for InsuranceFundDraw {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 100,
+        is_fixed_size: false,
+    };
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        bincode::deserialize(bytes.as_ref()).expect("Failed to deserialize InsuranceFundDraw")
+    }
+
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let serialized = bincode::serialize(self).expect("Failed to serialize InsuranceFundDraw");
+        Cow::Owned(serialized)
+    }
+}
@@ -0,0 +1,276 @@
+//! # Legacy State Functions
+//!
+//! These functions restore the state older versions of the canister saved with stable_save in their pre_upgrade hook
+//!
+//! Operation
+//!   - stable_save wrote the limit orders record ,the executable limit orders accounts and the best offers from the start of
+//!     the stable memory ,over the memory manager header
+//!   - on upgrade the saved state is decoded ,the memory manager header is rebuilt from the bucket allocations stored right
+//!     after it and the saved state is inserted into the stable structures that replaced it
+
+use candid::de::IDLDeserialize;
+use candid::utils::ArgumentDecoder;
+
+use crate::{
+    _update_highest_buy_offer_tick, _update_lowest_sell_offer_tick, store_tick_order,
+    EXECUTABLE_LIMIT_ORDERS_ACCOUNTS,
+};
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+use ic_stable_structures::Memory;
+
+type Tick = u64;
+type Subaccount = [u8; 32];
+
+/// The offset of the memory manager bucket allocations ,they are stored right after the memory manager header
+///
+/// Note
+///  - This is the layout of ic-stable-structures 0.6.8 ,the version is pinned in Cargo.toml
+const _MEMORY_MANAGER_BUCKET_ALLOCATIONS_OFFSET: u64 = 2080;
+
+const _MEMORY_MANAGER_MAX_NUM_BUCKETS: usize = 32768;
+
+const _MEMORY_MANAGER_BUCKET_SIZE_IN_PAGES: u64 = 128;
+
+const _MEMORY_MANAGER_UNALLOCATED_BUCKET: u8 = u8::MAX;
+
+const _WASM_PAGE_SIZE: u64 = 65536;
+
+/// Legacy Stable State
+///
+/// The limit orders record ,the executable limit orders accounts ,the highest buy offer and the lowest sell offer saved with
+/// stable_save by the pre_upgrade hook of older versions of the canister
+pub(crate) type LegacyStableState = (HashMap<Tick, Vec<Subaccount>>, Vec<Subaccount>, Tick, Tick);
+
+/// Restore Legacy Stable State
+///
+/// Reads the state saved with stable_save by older versions of the canister and rebuilds the memory manager header it overwrote
+///
+/// Params
+///  - Memory :The memory the memory manager is initialised over (the stable memory)
+///
+/// Returns
+///  - Legacy State :The saved state or none if the memory holds a valid memory manager
+///
+/// Note
+///  - Traps if the saved state does not fit in the header as the bucket allocations were overwritten too ,rolling back the upgrade
+///  - Must be called before the memory manager is first accessed ,as the memory manager clears the bucket allocations when the
+///    header is not valid
+pub(crate) fn _restore_legacy_stable_state<M: Memory>(memory: &M) -> Option<LegacyStableState> {
+    if memory.size() == 0 {
+        return None;
+    }
+
+    let mut header = [0u8; _MEMORY_MANAGER_BUCKET_ALLOCATIONS_OFFSET as usize];
+    memory.read(0, &mut header);
+
+    if header.starts_with(b"MGR") {
+        return None;
+    }
+
+    // decoding only succeeds if the saved state was written within the header
+    let legacy_state: LegacyStableState = IDLDeserialize::new(&header)
+        .and_then(|mut deserializer| ArgumentDecoder::decode(&mut deserializer))
+        .unwrap_or_else(|error| {
+            ic_cdk::trap(format!(
+                "failed to restore legacy stable state: {:?}",
+                error
+            ))
+        });
+
+    _rebuild_memory_manager_header(memory);
+
+    Some(legacy_state)
+}
+
+/// Rebuild Memory Manager Header
+///
+/// Rebuilds the memory manager header by replaying the bucket allocations on a memory manager over a scratch memory and
+/// copying the scratch header and bucket allocations into memory
+///
+/// Note
+///  - Buckets are allocated in order ,so growing each memory by a bucket in the order of the bucket allocations allocates
+///    the same buckets to the same memories
+///  - The size of each memory is set to the size of its allocated buckets
+fn _rebuild_memory_manager_header<M: Memory>(memory: &M) {
+    let mut bucket_allocations = vec![0u8; _MEMORY_MANAGER_MAX_NUM_BUCKETS];
+    memory.read(
+        _MEMORY_MANAGER_BUCKET_ALLOCATIONS_OFFSET,
+        &mut bucket_allocations,
+    );
+
+    let scratch_memory = HeaderMemory::default();
+    let memory_manager = MemoryManager::init(scratch_memory.clone());
+
+    for memory_id in bucket_allocations
+        .into_iter()
+        .take_while(|memory_id| *memory_id != _MEMORY_MANAGER_UNALLOCATED_BUCKET)
+    {
+        memory_manager
+            .get(MemoryId::new(memory_id))
+            .grow(_MEMORY_MANAGER_BUCKET_SIZE_IN_PAGES);
+    }
+
+    memory.write(0, &scratch_memory.page.borrow());
+}
+
+/// Insert Legacy Stable State
+///
+/// Inserts the state restored from older versions of the canister into the stable structures
+pub(crate) fn _insert_legacy_stable_state(legacy_state: LegacyStableState) {
+    let (limit_orders_accounts_record, executable_orders, highest_buy_offer, lowest_sell_offer) =
+        legacy_state;
+
+    for (tick, accounts) in limit_orders_accounts_record {
+        for account in accounts {
+            store_tick_order(tick, account);
+        }
+    }
+
+    EXECUTABLE_LIMIT_ORDERS_ACCOUNTS.with_borrow_mut(|reference| {
+        for account in executable_orders {
+            reference.insert(account, ());
+        }
+    });
+
+    _update_highest_buy_offer_tick(highest_buy_offer);
+    _update_lowest_sell_offer_tick(lowest_sell_offer);
+}
+
+/// Header Memory
+///
+/// A scratch memory that only keeps its first page ,which holds the memory manager header and bucket allocations
+///
+/// Note
+///  - The memory can grow to any size without allocating the buckets ,reading or writing past the first page panics
+#[derive(Clone, Default)]
+struct HeaderMemory {
+    page: Rc<RefCell<Vec<u8>>>,
+    size: Rc<Cell<u64>>,
+}
+
+impl Memory for HeaderMemory {
+    fn size(&self) -> u64 {
+        self.size.get()
+    }
+
+    fn grow(&self, pages: u64) -> i64 {
+        let previous_size = self.size.get();
+        if previous_size == 0 && pages > 0 {
+            self.page.borrow_mut().resize(_WASM_PAGE_SIZE as usize, 0);
+        }
+        self.size.set(previous_size + pages);
+        previous_size as i64
+    }
+
+    fn read(&self, offset: u64, dst: &mut [u8]) {
+        let offset = offset as usize;
+        dst.copy_from_slice(&self.page.borrow()[offset..offset + dst.len()]);
+    }
+
+    fn write(&self, offset: u64, src: &[u8]) {
+        let offset = offset as usize;
+        self.page.borrow_mut()[offset..offset + src.len()].copy_from_slice(src);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use candid::Encode;
+    use ic_stable_structures::{StableBTreeMap, VectorMemory};
+
+    fn _second_bucket_offset() -> u64 {
+        _MEMORY_MANAGER_BUCKET_SIZE_IN_PAGES * _WASM_PAGE_SIZE
+    }
+
+    fn _legacy_state() -> LegacyStableState {
+        let mut limit_orders_record = HashMap::new();
+        limit_orders_record.insert(20_000, vec![[1u8; 32], [2u8; 32]]);
+
+        (limit_orders_record, vec![[3u8; 32]], 19_990, 20_010)
+    }
+
+    // a memory manager with two memories ,the second spanning two buckets
+    fn _managed_memory() -> VectorMemory {
+        let memory = VectorMemory::default();
+        let memory_manager = MemoryManager::init(memory.clone());
+
+        let mut map_a: StableBTreeMap<u64, u64, _> =
+            StableBTreeMap::init(memory_manager.get(MemoryId::new(3)));
+        let mut map_b: StableBTreeMap<u64, u64, _> =
+            StableBTreeMap::init(memory_manager.get(MemoryId::new(7)));
+
+        map_a.insert(1, 10);
+        map_b.insert(2, 20);
+
+        let memory_b = memory_manager.get(MemoryId::new(7));
+        memory_b.grow(_MEMORY_MANAGER_BUCKET_SIZE_IN_PAGES);
+        memory_b.write(_second_bucket_offset(), &42u64.to_le_bytes());
+
+        memory
+    }
+
+    #[test]
+    fn test_valid_memory_manager_is_not_restored() {
+        let memory = _managed_memory();
+
+        assert!(_restore_legacy_stable_state(&memory).is_none());
+        assert!(_restore_legacy_stable_state(&VectorMemory::default()).is_none());
+    }
+
+    #[test]
+    fn test_restore_legacy_stable_state() {
+        let memory = _managed_memory();
+
+        let mut header_before = vec![0u8; _WASM_PAGE_SIZE as usize];
+        memory.read(0, &mut header_before);
+
+        // what stable_save wrote in the pre_upgrade hook of older versions
+        let (limit_orders_record, executable_orders, highest_buy_offer, lowest_sell_offer) =
+            _legacy_state();
+        let saved = Encode!(
+            &limit_orders_record,
+            &executable_orders,
+            &highest_buy_offer,
+            &lowest_sell_offer
+        )
+        .unwrap();
+        memory.write(0, &saved);
+
+        let legacy_state = _restore_legacy_stable_state(&memory).unwrap();
+        assert_eq!(legacy_state, _legacy_state());
+
+        // the bucket allocations are unchanged
+        let mut header_after = vec![0u8; _WASM_PAGE_SIZE as usize];
+        memory.read(0, &mut header_after);
+        let bucket_allocations = _MEMORY_MANAGER_BUCKET_ALLOCATIONS_OFFSET as usize;
+        assert_eq!(
+            header_after[bucket_allocations..],
+            header_before[bucket_allocations..]
+        );
+
+        // every memory is loaded with its data
+        let memory_manager = MemoryManager::init(memory);
+
+        let map_a: StableBTreeMap<u64, u64, _> =
+            StableBTreeMap::init(memory_manager.get(MemoryId::new(3)));
+        let map_b: StableBTreeMap<u64, u64, _> =
+            StableBTreeMap::init(memory_manager.get(MemoryId::new(7)));
+
+        assert_eq!(map_a.get(&1), Some(10));
+        assert_eq!(map_b.get(&2), Some(20));
+
+        let memory_b = memory_manager.get(MemoryId::new(7));
+        assert_eq!(memory_b.size(), 2 * _MEMORY_MANAGER_BUCKET_SIZE_IN_PAGES);
+
+        let mut word = [0u8; 8];
+        memory_b.read(_second_bucket_offset(), &mut word);
+        assert_eq!(u64::from_le_bytes(word), 42);
+    }
+}
@@ -1,27 +1,45 @@
 use bincode;
 use candid::{CandidType, Decode, Encode, Principal};
 use ic_cdk::call::Call;
-use ic_cdk::{export_candid, storage};
+use ic_cdk::export_candid;
 use serde::{Deserialize, Serialize};
 
 use ic_cdk_timers::TimerId;
 
 use sha2::{Digest, Sha256};
 
-use corelib::calc_lib::{_calc_interest, _percentage};
+use corelib::calc_lib::{_calc_interest, _calc_shares_value, _percentage};
 use corelib::constants::{_ONE_PERCENT, _PRICE_FACTOR};
-use corelib::order_lib::{CloseOrderParams, LimitOrder, OpenOrderParams};
-use corelib::price_lib::_equivalent;
-use corelib::swap_lib::{SwapParams, _get_best_offer};
+use corelib::depth_lib::_get_book_depth;
+use corelib::order_lib::{
+    _is_order_unfilled, _order_amounts, AmendOrderParams, CloseOrderParams, LimitOrder,
+    OpenOrderParams,
+};
+use corelib::price_lib::{_equivalent, _median};
+use corelib::store_lib::Clock;
+use corelib::swap_lib::{SwapParams, _get_best_offer, _simulate_swap};
 use corelib::tick_lib::{_compressed_tick, _def_max_tick};
 use types::{
-    FundingRateTracker, GetExchangeRateRequest, GetExchangeRateResult, MarketDetails, StateDetails,
-    TickDetails,
+    _read_appended_field, BookLevel, FundingRateTracker, GetExchangeRateRequest,
+    GetExchangeRateResult, MarketDetails, MarketError, OrderBookDepth, StateDetails, TickDetails,
+    TickState,
+};
+
+use triggers::{_schedule_position_triggers_check, PositionTriggers};
+
+use insurance_fund::{
+    _add_to_insurance_fund, _draw_from_insurance_fund, _get_insurance_fund_balance,
+    InsuranceFundDraw,
 };
 
+use legacy_state::{_insert_legacy_stable_state, _restore_legacy_stable_state};
+
+use history::{_record_trade, _update_candles, Candle, CandleInterval, Trade};
+
+use expiry::{_schedule_limit_positions_expiry_check, _set_limit_position_expiry};
+
 use std::borrow::Cow;
 use std::cell::RefCell;
-use std::collections::HashMap;
 use std::fmt::Debug;
 use std::time::Duration;
 
@@ -33,6 +51,8 @@ type Time = u64;
 type Amount = u128;
 type Tick = u64;
 type Subaccount = [u8; 32];
+type OrderId = u64;
+type TradeId = u64;
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
@@ -52,30 +72,105 @@ const _ACCOUNTS_POSITION_MEMORY: MemoryId = MemoryId::new(7);
 
 const _ACCOUNT_ERROR_LOGS_MEMORY: MemoryId = MemoryId::new(8);
 
-//const _EXECUTABLE_ORDERS_MEMORY: MemoryId = MemoryId::new(9);
+const _EXECUTABLE_ORDERS_MEMORY: MemoryId = MemoryId::new(9);
+
+const _POSITIONS_TRIGGERS_MEMORY: MemoryId = MemoryId::new(10);
+
+const _ACCOUNTS_LIMIT_ORDERS_MEMORY: MemoryId = MemoryId::new(11);
+
+const _TICKS_LIMIT_ORDERS_MEMORY: MemoryId = MemoryId::new(12);
+
+const _EXECUTABLE_ACCOUNTS_LIMIT_ORDERS_MEMORY: MemoryId = MemoryId::new(13);
+
+const _LIMIT_ORDER_ID_MEMORY: MemoryId = MemoryId::new(14);
+
+const _TRADES_MEMORY: MemoryId = MemoryId::new(15);
+
+const _ACCOUNTS_TRADES_MEMORY: MemoryId = MemoryId::new(16);
+
+const _CANDLES_MEMORY: MemoryId = MemoryId::new(17);
+
+const _INDEX_PRICE_MEMORY: MemoryId = MemoryId::new(18);
+
+const _STORAGE_VERSION_MEMORY: MemoryId = MemoryId::new(19);
+
+/// The version of the stored state ,see Migrate Storage
+const _STORAGE_VERSION: u32 = 1;
+
+const _INSURANCE_FUND_MEMORY: MemoryId = MemoryId::new(20);
+
+const _INSURANCE_FUND_DRAWS_MEMORY: MemoryId = MemoryId::new(21);
+
+const _FUNDING_HISTORY_MEMORY: MemoryId = MemoryId::new(22);
+
+const _LIMIT_ORDERS_RECORD_MEMORY: MemoryId = MemoryId::new(23);
+
+const _HIGHEST_BUY_OFFER_MEMORY: MemoryId = MemoryId::new(24);
+
+const _LOWEST_SELL_OFFER_MEMORY: MemoryId = MemoryId::new(25);
+
+const _ACCRUED_FEES_MEMORY: MemoryId = MemoryId::new(26);
+
+const _LIMIT_POSITIONS_EXPIRY_MEMORY: MemoryId = MemoryId::new(27);
+
+const _EXPIRING_LIMIT_POSITIONS_MEMORY: MemoryId = MemoryId::new(28);
 
 const ONE_SECOND: u64 = 1_000_000_000;
 
+const ONE_MINUTE: u64 = 60 * ONE_SECOND;
+
 const ONE_HOUR: u64 = 3_600_000_000_000;
 
+const ONE_DAY: u64 = 24 * ONE_HOUR;
+
 const _DEFAULT_SWAP_SLIPPAGE: u64 = 30_000; //0.3%
 
+const _DEFAULT_MAX_FUNDING_RATE: u64 = 75_000; //0.75%
+
+const _DEFAULT_FUNDING_DAMPING_FACTOR: u64 = 100 * _ONE_PERCENT; //100%
+
+const _MAX_BOOK_DEPTH_LEVELS: u32 = 200;
+
+const _POSITION_TRIGGERS_CHECK_INTERVAL: u64 = 10 * ONE_SECOND;
+
+const _MAX_TRIGGERED_POSITIONS_PER_CHECK: usize = 20;
+
+const _LIMIT_POSITIONS_EXPIRY_CHECK_INTERVAL: u64 = ONE_MINUTE;
+
+const _MAX_EXPIRED_POSITIONS_PER_CHECK: usize = 20;
+
+const _MAX_ACCOUNT_LIMIT_ORDERS: usize = 20;
+
+const _MAX_TRADES_PER_QUERY: u32 = 100;
+
+const _MAX_CANDLES_PER_QUERY: usize = 500;
+
+const _MAX_LIQUIDATION_SCAN: u32 = 500;
+
+const _MAX_INSURANCE_FUND_DRAWS_PER_QUERY: u32 = 100;
+
+const _MAX_FUNDING_SETTLEMENTS_PER_QUERY: u32 = 100;
+
+const _INDEX_PRICE_UPDATE_INTERVAL: u64 = 5 * ONE_MINUTE;
+
+const _MAX_INDEX_PRICE_AGE: u64 = 2 * _INDEX_PRICE_UPDATE_INTERVAL;
+
 thread_local! {
 
     static MEMORY_MANAGER:RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(MemoryManager::init(DefaultMemoryImpl::default())) ;
 
-    static ADMIN:RefCell<StableCell<Principal,Memory>> = RefCell::new(StableCell::new(MEMORY_MANAGER.with(|s|{
+    static ADMIN:RefCell<StableCell<Principal,Memory>> = RefCell::new(StableCell::init(MEMORY_MANAGER.with(|s|{
         s.borrow().get(_ADMIN_MEMORY)
     }),Principal::anonymous()).unwrap());
 
 
-    static MARKET_DETAILS:RefCell<StableCell<MarketDetails,Memory>> = RefCell::new(StableCell::new(MEMORY_MANAGER.with(|s|{
+    static MARKET_DETAILS:RefCell<StableCell<MarketDetails,Memory>> = RefCell::new(StableCell::init(MEMORY_MANAGER.with(|s|{
         s.borrow().get(_MARKET_DETAILS_MEMORY)
     }),MarketDetails::default()).unwrap());
 
 
         /// State details
-    static STATE_DETAILS:RefCell<StableCell<StateDetails,Memory>> = RefCell::new(StableCell::new(MEMORY_MANAGER.with(|s|{
+    static STATE_DETAILS:RefCell<StableCell<StateDetails,Memory>> = RefCell::new(StableCell::init(MEMORY_MANAGER.with(|s|{
         s.borrow().get(_STATE_DETAILS_MEMORY)
     }),StateDetails::default()).unwrap());
 
@@ -89,7 +184,7 @@ thread_local! {
     }))) ;
 
 
-    static FUNDING_RATE_TRACKER:RefCell<StableCell<FundingRateTracker,Memory>> = RefCell::new(StableCell::new(MEMORY_MANAGER.with(|s|{
+    static FUNDING_RATE_TRACKER:RefCell<StableCell<FundingRateTracker,Memory>> = RefCell::new(StableCell::init(MEMORY_MANAGER.with(|s|{
         s.borrow().get(_FUNDING_RATE_TRACKER_MEMORY)
     }),FundingRateTracker::default()).unwrap());
 
@@ -104,15 +199,111 @@ thread_local! {
         s.borrow().get(_ACCOUNT_ERROR_LOGS_MEMORY)
     })));
 
-    static EXECUTABLE_LIMIT_ORDERS_ACCOUNTS:RefCell<Vec<Subaccount>> = RefCell::new(Vec::new());
+    static POSITIONS_TRIGGERS:RefCell<StableBTreeMap<Subaccount,PositionTriggers,Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|s|{
+        s.borrow().get(_POSITIONS_TRIGGERS_MEMORY)
+    })));
+
+    static ACCOUNTS_LIMIT_ORDERS:RefCell<StableBTreeMap<(Subaccount,OrderId),AccountLimitOrder,Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|s|{
+        s.borrow().get(_ACCOUNTS_LIMIT_ORDERS_MEMORY)
+    })));
+
+    static TICKS_LIMIT_ORDERS:RefCell<StableBTreeMap<(Tick,Subaccount,OrderId),(),Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|s|{
+        s.borrow().get(_TICKS_LIMIT_ORDERS_MEMORY)
+    })));
+
+    static EXECUTABLE_ACCOUNTS_LIMIT_ORDERS:RefCell<StableBTreeMap<(Subaccount,OrderId),(),Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|s|{
+        s.borrow().get(_EXECUTABLE_ACCOUNTS_LIMIT_ORDERS_MEMORY)
+    })));
+
+    static LIMIT_ORDER_ID:RefCell<StableCell<OrderId,Memory>> = RefCell::new(StableCell::init(MEMORY_MANAGER.with(|s|{
+        s.borrow().get(_LIMIT_ORDER_ID_MEMORY)
+    }),0).unwrap());
+
+    static TRADES:RefCell<StableBTreeMap<TradeId,Trade,Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|s|{
+        s.borrow().get(_TRADES_MEMORY)
+    })));
+
+    static ACCOUNTS_TRADES:RefCell<StableBTreeMap<(Subaccount,TradeId),(),Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|s|{
+        s.borrow().get(_ACCOUNTS_TRADES_MEMORY)
+    })));
+
+    static CANDLES:RefCell<StableBTreeMap<(Time,Time),Candle,Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|s|{
+        s.borrow().get(_CANDLES_MEMORY)
+    })));
+
+    /// Index price tick and the time it was fetched
+    static INDEX_PRICE:RefCell<StableCell<(Tick,Time),Memory>> = RefCell::new(StableCell::init(MEMORY_MANAGER.with(|s|{
+        s.borrow().get(_INDEX_PRICE_MEMORY)
+    }),(0,0)).unwrap());
+
+    static INSURANCE_FUND:RefCell<StableCell<Amount,Memory>> = RefCell::new(StableCell::init(MEMORY_MANAGER.with(|s|{
+        s.borrow().get(_INSURANCE_FUND_MEMORY)
+    }),0).unwrap());
+
+    static ACCRUED_FEES:RefCell<StableCell<Amount,Memory>> = RefCell::new(StableCell::init(MEMORY_MANAGER.with(|s|{
+        s.borrow().get(_ACCRUED_FEES_MEMORY)
+    }),0).unwrap());
+
+    static INSURANCE_FUND_DRAWS:RefCell<StableBTreeMap<u64,InsuranceFundDraw,Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|s|{
+        s.borrow().get(_INSURANCE_FUND_DRAWS_MEMORY)
+    })));
+
+    static FUNDING_HISTORY:RefCell<StableBTreeMap<u64,FundingSettlement,Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|s|{
+        s.borrow().get(_FUNDING_HISTORY_MEMORY)
+    })));
+
+    static EXECUTABLE_LIMIT_ORDERS_ACCOUNTS:RefCell<StableBTreeMap<Subaccount,(),Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|s|{
+        s.borrow().get(_EXECUTABLE_ORDERS_MEMORY)
+    })));
 
     static PENDING_TIMER:RefCell<TimerId>= RefCell::new(TimerId::default());
 
-    static LIMIT_ORDERS_RECORD :RefCell<HashMap<Tick,Vec<Subaccount>>> = RefCell::new(HashMap::new());
+    static TRIGGERS_TIMER:RefCell<TimerId>= RefCell::new(TimerId::default());
+
+    static FUNDING_TIMER:RefCell<TimerId>= RefCell::new(TimerId::default());
+
+    static EXPIRY_TIMER:RefCell<TimerId>= RefCell::new(TimerId::default());
+
+    /// Expiry time of each account's limit position
+    static LIMIT_POSITIONS_EXPIRY:RefCell<StableBTreeMap<Subaccount,Time,Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|s|{
+        s.borrow().get(_LIMIT_POSITIONS_EXPIRY_MEMORY)
+    })));
+
+    /// Limit positions ordered by their expiry time
+    static EXPIRING_LIMIT_POSITIONS:RefCell<StableBTreeMap<(Time,Subaccount),(),Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|s|{
+        s.borrow().get(_EXPIRING_LIMIT_POSITIONS_MEMORY)
+    })));
+
+    static INDEX_PRICE_TIMER:RefCell<TimerId>= RefCell::new(TimerId::default());
+
+    static LIMIT_ORDERS_RECORD:RefCell<StableBTreeMap<(Tick,Subaccount),(),Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|s|{
+        s.borrow().get(_LIMIT_ORDERS_RECORD_MEMORY)
+    })));
+
+    static HIGHEST_BUY_OFFER:RefCell<StableCell<Tick,Memory>> = RefCell::new(StableCell::init(MEMORY_MANAGER.with(|s|{
+        s.borrow().get(_HIGHEST_BUY_OFFER_MEMORY)
+    }),0).unwrap());
 
-    static HIGHEST_BUY_OFFER:RefCell<Tick> = RefCell::new(0);
+    static LOWEST_SELL_OFFER:RefCell<StableCell<Tick,Memory>> = RefCell::new(StableCell::init(MEMORY_MANAGER.with(|s|{
+        s.borrow().get(_LOWEST_SELL_OFFER_MEMORY)
+    }),0).unwrap());
 
-    static LOWEST_SELL_OFFER:RefCell<Tick> = RefCell::new(0);
+    static STORAGE_VERSION:RefCell<StableCell<u32,Memory>> = RefCell::new(StableCell::init(MEMORY_MANAGER.with(|s|{
+        s.borrow().get(_STORAGE_VERSION_MEMORY)
+    }),0).unwrap());
 
 }
 
@@ -129,58 +320,62 @@ fn init(market_details: MarketDetails) {
     MARKET_DETAILS.with(|ref_market_details| {
         ref_market_details.borrow_mut().set(market_details).unwrap();
     });
+    STORAGE_VERSION.with_borrow_mut(|reference| reference.set(_STORAGE_VERSION).unwrap());
 }
 /////////////////////////////////////////////////////////////////////////
 ////////////////////////////////////////////////////////////
 /////////////////////////////////////////////////////////
-#[ic_cdk::pre_upgrade]
-fn pre_upgrade() {
-    let limit_orders_accounts_record: HashMap<u64, Vec<[u8; 32]>> =
-        LIMIT_ORDERS_RECORD.with_borrow(|reference| reference.clone());
-
-    let executable_orders =
-        EXECUTABLE_LIMIT_ORDERS_ACCOUNTS.with_borrow(|reference| reference.clone());
-
-    let highest_buy_offer = _get_highest_buy_offer_tick();
-    let lowest_sell_offer = _get_lowest_sell_offer_tick();
-    storage::stable_save((
-        limit_orders_accounts_record,
-        executable_orders,
-        highest_buy_offer,
-        lowest_sell_offer,
-    ))
-    .expect("error storing data");
-}
-
 #[ic_cdk::post_upgrade]
 fn post_upgrade() {
-    let limit_orders_accounts_record: HashMap<u64, Vec<Subaccount>>;
-
-    let executable_orders: Vec<Subaccount>;
-
-    let highest_buy_offer: Tick;
-
-    let lowest_sell_offer: Tick;
+    // the legacy state must be restored before the memory manager is first accessed
+    if let Some(legacy_state) = _restore_legacy_stable_state(&DefaultMemoryImpl::default()) {
+        _insert_legacy_stable_state(legacy_state);
+    }
 
-    (
-        limit_orders_accounts_record,
-        executable_orders,
-        highest_buy_offer,
-        lowest_sell_offer,
-    ) = storage::stable_restore().unwrap();
+    _migrate_storage();
 
-    LIMIT_ORDERS_RECORD.with_borrow_mut(|reference| {
-        *reference = limit_orders_accounts_record;
-    });
-    EXECUTABLE_LIMIT_ORDERS_ACCOUNTS.with_borrow_mut(|reference| {
-        *reference = executable_orders;
+    // state details written by an older layout are rewritten in the current layout
+    STATE_DETAILS.with_borrow_mut(|reference| {
+        let state_details = *reference.get();
+        reference.set(state_details).unwrap();
     });
 
-    // let StateDetails { current_tick, .. } = _get_state_details();
+    // timers do not persist across upgrades
+    _schedule_position_triggers_check();
+    _schedule_limit_orders_execution();
+    _schedule_index_price_refresh();
+    _schedule_funding_rate_settlement();
+    _schedule_limit_positions_expiry_check();
+}
+
+/// Migrate Storage
+///
+/// Runs the one time migrations for state stored by an older version of the canister and sets the storage version
+///
+/// Migrations
+///  - 1 :positions stored before the account index was added to PositionParameters are re-inserted with
+///    their account index
+fn _migrate_storage() {
+    let storage_version = STORAGE_VERSION.with_borrow(|reference| *reference.get());
+
+    if storage_version < 1 {
+        let positions: Vec<(Subaccount, PositionParameters)> =
+            ACCOUNTS_POSITION.with_borrow(|reference| reference.iter().collect());
+
+        for (account, mut position) in positions {
+            // the account index is found by checking every possible account index of the owner
+            let Some(account_index) =
+                (0..=u8::MAX).find(|index| position.owner._to_subaccount(*index) == account)
+            else {
+                continue;
+            };
 
-    _update_lowest_sell_offer_tick(lowest_sell_offer);
+            position.account_index = account_index;
+            _insert_account_position(account, position);
+        }
+    }
 
-    _update_highest_buy_offer_tick(highest_buy_offer);
+    STORAGE_VERSION.with_borrow_mut(|reference| reference.set(_STORAGE_VERSION).unwrap());
 }
 
 /// Get State Details
@@ -209,7 +404,9 @@ fn get_account_position_details(
         return None;
     };
 
-    let position_status = _convert_account_limit_position_to_market(account, true);
+    // an order whose amounts can not be calculated is not executed ,so it is reported as unfilled
+    let position_status = _convert_account_limit_position_to_market(account, true)
+        .unwrap_or(PositionStatus::UNFILLED);
 
     let StateDetails {
         max_leveragex10, ..
@@ -243,6 +440,124 @@ fn get_best_offers() -> (Tick, Tick) {
     );
 }
 
+/// Get Mark Price
+///
+/// Returns the mark price tick ,the median of the index price ,the book mid price and the last trade tick
+#[ic_cdk::query(name = "getMarkPrice")]
+fn get_mark_price() -> Tick {
+    let MarketDetails { tick_spacing, .. } = _get_market_details();
+
+    return _get_mark_tick() * tick_spacing;
+}
+
+/// Get Funding History
+///
+/// Returns funding rate settlements in the order they were settled
+///
+/// Params
+///  - Start :The id of the first settlement to return
+///  - Limit :The max number of settlements to return ,capped at _MAX_FUNDING_SETTLEMENTS_PER_QUERY
+#[ic_cdk::query(name = "getFundingHistory")]
+fn get_funding_history(start: u64, limit: u32) -> Vec<FundingSettlement> {
+    let limit = limit.min(_MAX_FUNDING_SETTLEMENTS_PER_QUERY) as usize;
+
+    FUNDING_HISTORY.with_borrow(|reference| {
+        reference
+            .range(start..)
+            .take(limit)
+            .map(|(_, settlement)| settlement)
+            .collect()
+    })
+}
+
+/// Get Current Funding Rate
+///
+/// Returns the funding rate that would be settled now ,calculated from the mark price and the last index price
+///
+/// Returns
+///  - Funding Rate :The funding rate in percentage ,positive if longs pay shorts and negative otherwise
+///  or none if the index price has not been fetched yet
+#[ic_cdk::query(name = "getCurrentFundingRate")]
+fn get_current_funding_rate() -> Option<i64> {
+    let (index_tick, _) = INDEX_PRICE.with_borrow(|reference| *reference.get());
+
+    if index_tick == 0 {
+        return None;
+    }
+
+    // the index tick is uncompressed ,so the mark tick is uncompressed before comparing
+    let MarketDetails { tick_spacing, .. } = _get_market_details();
+    let mark_tick = _get_mark_tick() * tick_spacing;
+
+    return Some(_calculate_funding_rate(
+        mark_tick as u128,
+        index_tick as u128,
+        _get_state_details(),
+    ));
+}
+
+/// Get Order Book Depth
+///
+/// Returns the liquidity on both sides of the book starting from the best offers
+///
+/// Params
+///  - Levels :The max number of levels to return per side (capped at _MAX_BOOK_DEPTH_LEVELS)
+///  - Grouping :The size of each price bucket in ticks ,if set to none each initialised tick is its own level
+#[ic_cdk::query(name = "getOrderBookDepth")]
+fn get_order_book_depth(levels: u32, grouping: Option<Tick>) -> OrderBookDepth {
+    let MarketDetails { tick_spacing, .. } = _get_market_details();
+
+    let levels = levels.min(_MAX_BOOK_DEPTH_LEVELS) as usize;
+
+    let group_size = grouping
+        .map(|x| _compressed_tick(x, tick_spacing))
+        .unwrap_or(1);
+
+    let to_book_levels = |depth: Vec<(Tick, Amount)>, side: TickState| -> Vec<BookLevel> {
+        let mut cumulative_liquidity = 0;
+        depth
+            .into_iter()
+            .map(|(tick, liquidity)| {
+                cumulative_liquidity += liquidity;
+                BookLevel {
+                    tick: tick * tick_spacing,
+                    side,
+                    liquidity,
+                    cumulative_liquidity,
+                }
+            })
+            .collect()
+    };
+
+    let (bids_depth, asks_depth) = TICKS_DETAILS.with_borrow(|ticks_details| {
+        INTEGRAL_BITMAPS.with_borrow(|integrals_bitmaps| {
+            (
+                _get_book_depth(
+                    false,
+                    _get_highest_buy_offer_tick(),
+                    levels,
+                    group_size,
+                    integrals_bitmaps,
+                    ticks_details,
+                ),
+                _get_book_depth(
+                    true,
+                    _get_lowest_sell_offer_tick(),
+                    levels,
+                    group_size,
+                    integrals_bitmaps,
+                    ticks_details,
+                ),
+            )
+        })
+    });
+
+    return OrderBookDepth {
+        bids: to_book_levels(bids_depth, TickState::BUY),
+        asks: to_book_levels(asks_depth, TickState::SELL),
+    };
+}
+
 /// Open PositionDetails functions
 ///
 /// opens a new position for user (given that user has no existing position)
@@ -251,6 +566,11 @@ fn get_best_offers() -> (Tick, Tick) {
 /// - Leverage :: The leverage for the required position multiplies by 10 i.e a 1.5 levarage is 1.5 * 10 = 15
 /// - Long :: Indicating if its a long position or not ,true if long and false otherwise
 /// - Order Type :: the type of order to create
+/// - Stop Loss Tick :: optional tick at which the position is closed automatically to limit losses
+/// - Take Profit Tick :: optional tick at which the position is closed automatically to take profit
+/// - Execution :: optional execution flag ,limit orders only accept post only and market orders accept
+///  immediate or cancel and fill or kill ,none defaults to post only for limit orders and immediate or cancel for market orders
+/// - Expiry :: optional time (in nanoseconds) after which an unfilled limit order is cancelled ,none keeps the order until it is filled or closed
 ///
 /// Returns
 ///  - Position:the details of the position
@@ -258,37 +578,75 @@ fn get_best_offers() -> (Tick, Tick) {
 /// Note
 ///  - If Order type is a limit order ,max tick coinsides with the reference tick for the limit order
 ///  - ANON TICKS are for future purposes and have no effect for now
+///  - Limit positions are always post only ,an order at a tick that would be executed immediately is rejected with CrossesBook
+///    as before the execution flag was added ,the check is now made before the collateral is taken from the vault
+///  - To open at or through the best offer use openMarketPosition with a max tick
 #[ic_cdk::update(name = "openLimitPosition")]
+#[allow(clippy::too_many_arguments)]
 async fn open_limit_position(
     account_index: u8,
     long: bool,
     collateral_value: Amount,
     leveragex10: u8,
     max_tick: Tick,
-) -> Result<PositionParameters, &'static str> {
+    stop_loss_tick: Option<Tick>,
+    take_profit_tick: Option<Tick>,
+    execution: Option<ExecutionFlag>,
+    expiry: Option<Time>,
+) -> Result<PositionParameters, MarketError> {
     let user = ic_cdk::api::msg_caller();
 
     let account = user._to_subaccount(account_index);
 
-    let debt_value = (u128::from(leveragex10 - 10) * collateral_value) / 10;
-
     let market_details = _get_market_details();
 
     let vault = Vault::init(market_details.vault_id);
 
+    if expiry.is_some_and(|expiry| expiry <= ic_cdk::api::time()) {
+        return Err(MarketError::InvalidExpiry);
+    }
+
     let MarketDetails { tick_spacing, .. } = _get_market_details();
     let entry_tick = _compressed_tick(max_tick, tick_spacing);
 
+    let triggers = PositionTriggers::init(
+        user,
+        account_index,
+        stop_loss_tick,
+        take_profit_tick,
+        tick_spacing,
+    );
+
+    if !triggers._valid_for(long, entry_tick) {
+        return Err(MarketError::InvalidTriggers);
+    }
+
+    let ExecutionFlag::PostOnly = execution.unwrap_or(ExecutionFlag::PostOnly) else {
+        return Err(MarketError::InvalidExecutionFlag);
+    };
+
+    if _crosses_book(long, entry_tick) {
+        return Err(MarketError::CrossesBook);
+    }
+
+    // working limit orders fill into a market position (see Account Limit Order Functions)
+    if _get_account_limit_orders_direction(&account).is_some() {
+        return Err(MarketError::LimitOrdersConflict);
+    }
+
     let interest_rate =
         match _open_position_checks(user, account, vault, collateral_value, leveragex10).await {
             Err(error) => return Err(error),
             Ok(rate) => rate,
         };
 
-    let path = || -> Option<(PositionParameters, Vec<Tick>)> {
+    let debt_value = (u128::from(leveragex10 - 10) * collateral_value) / 10;
+
+    let path = || -> Result<(PositionParameters, Vec<Tick>), MarketError> {
         if long {
             _open_limit_long_position(
                 account,
+                account_index,
                 collateral_value,
                 debt_value,
                 interest_rate,
@@ -297,6 +655,7 @@ async fn open_limit_position(
         } else {
             _open_limit_short_position(
                 account,
+                account_index,
                 collateral_value,
                 debt_value,
                 interest_rate,
@@ -305,79 +664,129 @@ async fn open_limit_position(
         }
     };
 
-    let Some((position, _)) = path() else {
-        vault.manage_position_update(
-            user,
-            account_index,
-            collateral_value,
-            ManageDebtParams::init(debt_value, debt_value, debt_value),
-        );
+    let (position, _) = match path() {
+        Ok(result) => result,
+        Err(error) => {
+            vault.manage_position_update(
+                user,
+                account_index,
+                collateral_value,
+                ManageDebtParams::init(debt_value, debt_value, debt_value),
+            );
 
-        return Err("Failed to open position");
+            return Err(error);
+        }
     };
-    store_tick_order(max_tick, account);
+    store_tick_order(entry_tick, account);
+    _set_position_triggers(account, triggers);
+    _set_limit_position_expiry(account, expiry);
     return Ok(position);
 }
 
 #[ic_cdk::update(name = "openMarketPosition")]
+#[allow(clippy::too_many_arguments)]
 async fn open_market_position(
     account_index: u8,
     long: bool,
     collateral_value: Amount,
     leveragex10: u8,
     max_tick: Option<Tick>,
-) -> Result<PositionParameters, &'static str> {
+    stop_loss_tick: Option<Tick>,
+    take_profit_tick: Option<Tick>,
+    execution: Option<ExecutionFlag>,
+) -> Result<PositionParameters, MarketError> {
     let user = ic_cdk::api::msg_caller();
 
     let account = user._to_subaccount(account_index);
 
-    let debt_value = (u128::from(leveragex10 - 10) * collateral_value) / 10;
-
     let market_details = _get_market_details();
 
     let vault = Vault::init(market_details.vault_id);
 
+    let triggers = PositionTriggers::init(
+        user,
+        account_index,
+        stop_loss_tick,
+        take_profit_tick,
+        market_details.tick_spacing,
+    );
+
+    // the expected entry tick is the best offer on the opposite side of the book
+    let reference_tick = if long {
+        _get_lowest_sell_offer_tick()
+    } else {
+        _get_highest_buy_offer_tick()
+    };
+
+    if !triggers._valid_for(long, reference_tick) {
+        return Err(MarketError::InvalidTriggers);
+    }
+
+    if _get_account_limit_orders_direction(&account).is_some_and(|orders_long| orders_long != long)
+    {
+        return Err(MarketError::DirectionConflict);
+    }
+
+    let fill_or_kill = match execution.unwrap_or(ExecutionFlag::ImmediateOrCancel) {
+        ExecutionFlag::PostOnly => return Err(MarketError::InvalidExecutionFlag),
+        ExecutionFlag::ImmediateOrCancel => false,
+        ExecutionFlag::FillOrKill => true,
+    };
+
     let interest_rate =
         match _open_position_checks(user, account, vault, collateral_value, leveragex10).await {
             Err(error) => return Err(error),
             Ok(rate) => rate,
         };
 
-    let path = || -> Option<(PositionParameters, Vec<Tick>)> {
+    let debt_value = (u128::from(leveragex10 - 10) * collateral_value) / 10;
+
+    let path = || -> Result<(PositionParameters, Vec<Tick>, Amount), MarketError> {
         if long {
             _open_market_long_position(
                 account,
+                account_index,
                 collateral_value,
                 debt_value,
                 interest_rate,
                 max_tick,
+                fill_or_kill,
             )
         } else {
             _open_market_short_position(
                 account,
+                account_index,
                 collateral_value,
                 debt_value,
                 interest_rate,
                 max_tick,
+                fill_or_kill,
             )
         }
     };
 
-    let Some((position, crossed_ticks)) = path() else {
-        vault.manage_position_update(
-            user,
-            account_index,
-            collateral_value,
-            ManageDebtParams::init(debt_value, debt_value, debt_value),
-        );
+    let (position, crossed_ticks, taker_fee) = match path() {
+        Ok(result) => result,
+        Err(error) => {
+            vault.manage_position_update(
+                user,
+                account_index,
+                collateral_value,
+                ManageDebtParams::init(debt_value, debt_value, debt_value),
+            );
 
-        return Err("Failed to open position");
+            return Err(error);
+        }
     };
 
     _schedule_execution_for_ticks_orders(crossed_ticks);
 
-    if position.debt_value != debt_value {
-        let un_used_collateral = collateral_value - position.collateral_value;
+    _set_position_triggers(account, triggers);
+
+    // the taker fee is retained from the collateral
+    let un_used_collateral = collateral_value - position.collateral_value - taker_fee;
+
+    if position.debt_value != debt_value || un_used_collateral != 0 {
         vault.manage_position_update(
             user,
             account_index,
@@ -402,101 +811,1167 @@ async fn open_market_position(
 ///  - If it is partially filled ,the position_type is converted into a market position with the amount filled as the entire position value and the ampount remaining is sent back    
 
 #[ic_cdk::update(name = "closeLimitPosition")]
-async fn close_limit_position(account_index: u8) -> Amount {
+async fn close_limit_position(account_index: u8) -> Result<Amount, MarketError> {
     let user = ic_cdk::api::msg_caller();
 
     let account = user._to_subaccount(account_index);
 
-    let mut position = _get_account_position(&account).unwrap();
+    let Some(mut position) = _get_account_position(&account) else {
+        return Err(MarketError::NoPosition);
+    };
 
     let market_details = _get_market_details();
 
     let vault = Vault::init(market_details.vault_id);
     if let PositionOrderType::Market = position.order_type {
-        return 0;
+        return Err(MarketError::NotLimitPosition);
     };
 
-    let (removed_collateral, manage_debt_params) = if position.long {
-        _close_limit_long_position(account, &mut position)
-    } else {
-        _close_limit_short_position(account, &mut position)
-    };
+    return _close_limit_position(user, account_index, &mut position, vault);
+}
 
-    remove_tick_order(position.entry_tick, account);
+/// Get Limit Position Expiry
+///
+/// Returns the time after which an account's limit position is cancelled if not filled ,none if it has no expiry
+#[ic_cdk::query(name = "getLimitPositionExpiry")]
+fn get_limit_position_expiry(user: Principal, account_index: u8) -> Option<Time> {
+    let account = user._to_subaccount(account_index);
 
-    if manage_debt_params.amount_repaid != 0 {
-        vault.manage_position_update(user, account_index, removed_collateral, manage_debt_params);
-    }
-    return removed_collateral;
+    LIMIT_POSITIONS_EXPIRY.with_borrow(|reference| reference.get(&account))
 }
 
-#[ic_cdk::update(name = "closeMarketPosition")]
-async fn close_market_position(account_index: u8, max_tick: Option<Tick>) -> Amount {
+/// Amend Limit Position
+///
+/// Moves the order of the caller's limit position to a new tick and/or reduces its size without closing and reopening the position
+///
+/// Params
+///  - Account Index :The index of the account holding the position
+///  - Tick :The new reference tick of the order
+///  - Collateral Value :The new collateral value of the position ,not greater than the current collateral value
+///
+/// Returns
+///  - Position :the details of the amended position
+///
+/// Note
+///  - The debt is reduced in proportion to the collateral so the leverage of the position is unchanged
+///  - The removed collateral and debt are sent back to the vault in a single update
+///  - If only the size is reduced at the same tick ,the order keeps its queue position at the tick
+///  - An order that has been partially or fully filled can not be amended
+#[ic_cdk::update(name = "amendLimitPosition")]
+fn amend_limit_position(
+    account_index: u8,
+    tick: Tick,
+    collateral_value: Amount,
+) -> Result<PositionParameters, MarketError> {
     let user = ic_cdk::api::msg_caller();
 
     let account = user._to_subaccount(account_index);
 
-    let mut position = _get_account_position(&account).unwrap();
+    let has_pending_error =
+        ACCOUNTS_ERROR_LOGS.with_borrow(|reference| reference.contains_key(&account));
 
-    let market_details = _get_market_details();
+    if has_pending_error {
+        return Err(MarketError::PendingErrorLog);
+    }
 
-    let vault = Vault::init(market_details.vault_id);
-    if let PositionOrderType::Market = position.order_type {
-        let (collateral_value, crossed_ticks, manage_debt_params) = if position.long {
-            _close_market_long_position(account, &mut position, max_tick)
-        } else {
-            _close_market_short_position(account, &mut position, max_tick)
-        };
+    let Some(mut position) = _get_account_position(&account) else {
+        return Err(MarketError::NoPosition);
+    };
 
-        _schedule_execution_for_ticks_orders(crossed_ticks);
+    let PositionOrderType::Limit(mut order) = position.order_type else {
+        return Err(MarketError::NotLimitPosition);
+    };
 
-        if manage_debt_params.amount_repaid != 0 {
-            vault.manage_position_update(user, account_index, collateral_value, manage_debt_params);
-        }
+    if collateral_value == 0 || collateral_value > position.collateral_value {
+        return Err(MarketError::InvalidAmount);
+    }
 
-        return collateral_value;
-    } else {
-        return 0;
+    if collateral_value < _get_state_details().min_collateral {
+        return Err(MarketError::CollateralTooSmall);
     }
-}
 
-/// Liquidate Function
-///
-/// liquidates an account's position to avoid bad debt by checking if the current leverage exceeds the max leverage
-///
-/// Note : Position is closed at the current tick
-#[ic_cdk::update(name = "liquidatePosition")]
-fn liquidate_position(user: Principal, account_index: u8) -> bool {
-    let account = user._to_subaccount(account_index);
-    let state_details = _get_state_details();
+    let MarketDetails {
+        tick_spacing,
+        vault_id,
+        ..
+    } = _get_market_details();
 
-    let market_details = _get_market_details();
+    let entry_tick = _compressed_tick(tick, tick_spacing);
 
-    let position =
-        _get_account_position(&account).expect("user has no position with this subaccount");
+    if let Some(triggers) = _get_position_triggers(&account) {
+        if !triggers._valid_for(position.long, entry_tick) {
+            return Err(MarketError::InvalidTriggers);
+        }
+    }
 
-    let (to_liquidate, collateral_remaining, net_debt_value) =
-        _liquidation_status(position, state_details.max_leveragex10);
+    if !TICKS_DETAILS.with_borrow(|ticks_details| _is_order_unfilled(&order, ticks_details)) {
+        return Err(MarketError::OrderFilled);
+    }
 
-    if to_liquidate {
-        let vault = Vault::init(market_details.vault_id);
+    let debt_value = (position.debt_value * collateral_value) / position.collateral_value;
 
-        let (collateral, amount_repaid) = if collateral_remaining > 0 {
-            (collateral_remaining.abs() as u128, net_debt_value)
-        } else {
-            (0, net_debt_value - (collateral_remaining.abs() as u128))
-        };
+    let order_size = _limit_order_size(position.long, collateral_value, debt_value, entry_tick)?;
 
-        let manage_debt_params =
-            ManageDebtParams::init(position.debt_value, net_debt_value, amount_repaid);
+    if entry_tick == position.entry_tick {
+        // reducing in place keeps the queue position of the order
+        if order_size < order.order_size && !_reduce_order(&mut order, order_size) {
+            return Err(MarketError::OrderFilled);
+        }
+    } else {
+        if _crosses_book(position.long, entry_tick) {
+            return Err(MarketError::CrossesBook);
+        }
 
-        _calc_position_realised_value(position.volume_share, position.long);
-        vault.manage_position_update(user, account_index, collateral, manage_debt_params);
+        _close_order(&order)?;
+        remove_tick_order(position.entry_tick, account);
 
-        _remove_account_position(&account);
-        return true;
-    }
-    return false;
+        let Some(new_order) = _place_order_on_book(position.long, order_size, entry_tick) else {
+            // unreachable as crossing is checked above
+            return Err(MarketError::CrossesBook);
+        };
+
+        order = new_order;
+        store_tick_order(entry_tick, account);
+    }
+
+    let removed_collateral = position.collateral_value - collateral_value;
+    let removed_debt = position.debt_value - debt_value;
+
+    position.entry_tick = entry_tick;
+    position.collateral_value = collateral_value;
+    position.debt_value = debt_value;
+    position.order_type = PositionOrderType::Limit(order);
+
+    _insert_account_position(account, position);
+
+    if removed_collateral != 0 || removed_debt != 0 {
+        let vault = Vault::init(vault_id);
+        vault.manage_position_update(
+            user,
+            account_index,
+            removed_collateral,
+            ManageDebtParams::init(removed_debt, removed_debt, removed_debt),
+        );
+    }
+
+    return Ok(position);
+}
+
+#[ic_cdk::update(name = "closeMarketPosition")]
+async fn close_market_position(
+    account_index: u8,
+    max_tick: Option<Tick>,
+) -> Result<Amount, MarketError> {
+    let user = ic_cdk::api::msg_caller();
+
+    let account = user._to_subaccount(account_index);
+
+    let Some(mut position) = _get_account_position(&account) else {
+        return Err(MarketError::NoPosition);
+    };
+
+    let market_details = _get_market_details();
+
+    let vault = Vault::init(market_details.vault_id);
+    if let PositionOrderType::Market = position.order_type {
+        return _close_market_position(user, account_index, &mut position, max_tick, vault);
+    } else {
+        return Err(MarketError::NotMarketPosition);
+    }
+}
+
+/// Close Market Position Partially
+///
+/// Closes part of a market position and sends back the profit on the closed part
+///
+/// Params
+///  - Account Index :The index of the account holding the position
+///  - Close Amount :Either the fraction of the position to close or the value (in quote asset) of the position to close
+///  - Max Tick :max executing tick ,if set to none the default max tick is used
+///
+/// Returns
+///  - Profit :The amount sent to the position owner
+///
+/// Note
+///  - If the close amount covers the entire position ,the position is closed fully
+#[ic_cdk::update(name = "closeMarketPositionPartially")]
+async fn close_market_position_partially(
+    account_index: u8,
+    close_amount: CloseAmount,
+    max_tick: Option<Tick>,
+) -> Result<Amount, MarketError> {
+    let user = ic_cdk::api::msg_caller();
+
+    let account = user._to_subaccount(account_index);
+
+    let Some(mut position) = _get_account_position(&account) else {
+        return Err(MarketError::NoPosition);
+    };
+
+    if let PositionOrderType::Limit(_) = position.order_type {
+        return Err(MarketError::NotMarketPosition);
+    }
+
+    let close_share = match _close_amount_to_share(&position, close_amount) {
+        Ok(close_share) => close_share,
+        Err(error) => return Err(error),
+    };
+
+    let market_details = _get_market_details();
+
+    let vault = Vault::init(market_details.vault_id);
+
+    if close_share >= position.volume_share {
+        return _close_market_position(user, account_index, &mut position, max_tick, vault);
+    }
+
+    let (profit, crossed_ticks, manage_debt_params) =
+        _close_market_position_partially(account, &mut position, close_share, max_tick)?;
+
+    _schedule_execution_for_ticks_orders(crossed_ticks);
+
+    if profit != 0 || manage_debt_params.amount_repaid != 0 {
+        vault.manage_position_update(user, account_index, profit, manage_debt_params);
+    }
+
+    return Ok(profit);
+}
+
+/// Add Position Margin
+///
+/// Adds collateral to a market position ,the added collateral is utilised to repay the position debt thereby reducing its leverage
+/// and any amount above the debt is kept on the position as extra margin
+///
+/// Params
+///  - Account Index :The index of the account holding the position
+///  - Amount :The amount of collateral to add
+///
+/// Returns
+///  - Position :The updated position
+#[ic_cdk::update(name = "addPositionMargin")]
+async fn add_position_margin(
+    account_index: u8,
+    amount: Amount,
+) -> Result<PositionParameters, MarketError> {
+    let user = ic_cdk::api::msg_caller();
+
+    _add_position_margin(user, account_index, amount).await
+}
+
+/// Remove Position Margin
+///
+/// Withdraws collateral from a market position by borrowing the amount from the vault ,thereby increasing its leverage
+///
+/// Params
+///  - Account Index :The index of the account holding the position
+///  - Amount :The amount of collateral to withdraw
+///
+/// Returns
+///  - Position :The updated position
+///
+/// Note
+///  - The resulting leverage must be less than the max leverage and the remaining collateral can not be less than the min collateral
+///  - Unrealised profit above the position's collateral can only be taken by closing the position (fully or partially)
+#[ic_cdk::update(name = "removePositionMargin")]
+async fn remove_position_margin(
+    account_index: u8,
+    amount: Amount,
+) -> Result<PositionParameters, MarketError> {
+    let user = ic_cdk::api::msg_caller();
+
+    _remove_position_margin(user, account_index, amount).await
+}
+
+/// Change Position Leverage
+///
+/// Changes the leverage of a market position by adding or removing margin while keeping the position size
+///
+/// Params
+///  - Account Index :The index of the account holding the position
+///  - Leverage :The new leverage for the position multiplied by 10
+///
+/// Returns
+///  - Position :The updated position
+#[ic_cdk::update(name = "changePositionLeverage")]
+async fn change_position_leverage(
+    account_index: u8,
+    leveragex10: u8,
+) -> Result<PositionParameters, MarketError> {
+    let user = ic_cdk::api::msg_caller();
+
+    let account = user._to_subaccount(account_index);
+
+    let StateDetails {
+        max_leveragex10, ..
+    } = _get_state_details();
+
+    if leveragex10 < 10 {
+        return Err(MarketError::InvalidLeverage);
+    }
+
+    if leveragex10 >= max_leveragex10 {
+        return Err(MarketError::LeverageTooHigh);
+    }
+
+    let Some(position) = _get_account_position(&account) else {
+        return Err(MarketError::NoPosition);
+    };
+
+    let (to_liquidate, current_collateral_value, net_debt_value) =
+        _liquidation_status(position, max_leveragex10);
+
+    if to_liquidate || current_collateral_value <= 0 {
+        return Err(MarketError::PositionLiquidatable);
+    }
+
+    let current_collateral_value = current_collateral_value as u128;
+
+    let position_value = net_debt_value + current_collateral_value;
+
+    let target_collateral_value = (position_value * 10) / u128::from(leveragex10);
+
+    if target_collateral_value > current_collateral_value {
+        _add_position_margin(
+            user,
+            account_index,
+            target_collateral_value - current_collateral_value,
+        )
+        .await
+    } else if target_collateral_value < current_collateral_value {
+        _remove_position_margin(
+            user,
+            account_index,
+            current_collateral_value - target_collateral_value,
+        )
+        .await
+    } else {
+        Ok(position)
+    }
+}
+
+/// Place Limit Order
+///
+/// Places an additional limit order for an account ,an account can have several working limit orders
+/// and each of them fills into the account's market position
+///
+/// Params
+///  - Account Index :The index of the account placing the order
+///  - Long :true for a buy order and false for a sell order
+///  - Collateral Value :The amount in collatreal token to utilise as collateral
+///  - Leverage :The leverage for the order multiplied by 10
+///  - Tick :The reference tick of the order
+///  - Execution :Optional execution flag ,only post only is accepted and none defaults to post only
+///
+/// Returns
+///  - Order ID :The id of the order within the account
+///
+/// Note
+///  - All working orders and the account's position must be in the same direction
+///  - Orders can not be placed while the account has an open limit position (see openLimitPosition)
+///  - An order at a tick that would be executed immediately is rejected with CrossesBook
+#[ic_cdk::update(name = "placeLimitOrder")]
+async fn place_limit_order(
+    account_index: u8,
+    long: bool,
+    collateral_value: Amount,
+    leveragex10: u8,
+    tick: Tick,
+    execution: Option<ExecutionFlag>,
+) -> Result<OrderId, MarketError> {
+    let user = ic_cdk::api::msg_caller();
+
+    let account = user._to_subaccount(account_index);
+
+    if let Err(error) = _place_limit_order_checks(&account, long) {
+        return Err(error);
+    }
+
+    let ExecutionFlag::PostOnly = execution.unwrap_or(ExecutionFlag::PostOnly) else {
+        return Err(MarketError::InvalidExecutionFlag);
+    };
+
+    let market_details = _get_market_details();
+
+    if _crosses_book(long, _compressed_tick(tick, market_details.tick_spacing)) {
+        return Err(MarketError::CrossesBook);
+    }
+
+    let vault = Vault::init(market_details.vault_id);
+
+    let interest_rate =
+        match _collateral_and_debt_checks(user, vault, collateral_value, leveragex10).await {
+            Err(error) => return Err(error),
+            Ok(rate) => rate,
+        };
+
+    let debt_value = (u128::from(leveragex10 - 10) * collateral_value) / 10;
+
+    let entry_tick = _compressed_tick(tick, market_details.tick_spacing);
+
+    // account could have changed while awaiting the vault
+    let order = _place_limit_order_checks(&account, long)
+        .and_then(|_| _open_limit_order(long, collateral_value, debt_value, entry_tick));
+
+    let order = match order {
+        Ok(order) => order,
+        Err(error) => {
+            vault.manage_position_update(
+                user,
+                account_index,
+                collateral_value,
+                ManageDebtParams::init(debt_value, debt_value, debt_value),
+            );
+
+            return Err(error);
+        }
+    };
+
+    let order_id = _next_limit_order_id();
+
+    _insert_account_limit_order(
+        account,
+        AccountLimitOrder {
+            owner: user,
+            account_index,
+            order_id,
+            long,
+            collateral_value,
+            debt_value,
+            interest_rate,
+            order,
+            timestamp: ic_cdk::api::time(),
+            reduce_only: false,
+            close_share: 0,
+        },
+    );
+
+    return Ok(order_id);
+}
+
+/// Cancel Limit Order
+///
+/// Cancels a working limit order of an account
+///
+/// Returns
+///  - Removed Collateral :The amount of collateral sent back to the account owner
+///
+/// Note
+///  - If the order has been partially filled ,the filled amount is added to the account's position and the unfilled amount is sent back
+#[ic_cdk::update(name = "cancelLimitOrder")]
+async fn cancel_limit_order(account_index: u8, order_id: OrderId) -> Result<Amount, MarketError> {
+    let user = ic_cdk::api::msg_caller();
+
+    let account = user._to_subaccount(account_index);
+
+    let Some(account_order) = _get_account_limit_order(&account, order_id) else {
+        return Err(MarketError::OrderNotFound);
+    };
+
+    return _execute_account_limit_order(account, account_order);
+}
+
+/// Place Reduce Only Order
+///
+/// Places a limit order on the opposite side of the book that closes part of the caller's market position when filled
+///
+/// Params
+///  - Account Index :The index of the account holding the position
+///  - Close Amount :Either the fraction of the position to close or the value (in quote asset) of the position to close
+///  - Tick :The tick to place the order at
+///
+/// Returns
+///  - Order Id :The id of the order
+///
+/// Note
+///  - The close amount is capped so that all reduce only orders of the position together never exceed the position
+///  - A fill only ever closes the position ,the share closed is capped by the remaining volume share of the position
+///    and the value of any fill beyond it is paid out to the owner
+///  - Filled reduce only orders are settled before the position is closed and unfilled ones are cancelled once it is closed
+#[ic_cdk::update(name = "placeReduceOnlyOrder")]
+fn place_reduce_only_order(
+    account_index: u8,
+    close_amount: CloseAmount,
+    tick: Tick,
+) -> Result<OrderId, MarketError> {
+    let user = ic_cdk::api::msg_caller();
+
+    return _place_reduce_only_order(user, account_index, close_amount, tick);
+}
+
+/// Place Take Profit Order
+///
+/// Places a limit order on the opposite side of the book that closes the caller's market position when filled
+///
+/// Params
+///  - Account Index :The index of the account holding the position
+///  - Tick :The tick to place the order at
+///
+/// Returns
+///  - Order Id :The id of the order
+///
+/// Note
+///  - For a long position the tick must be above and for a short position below the entry tick ,otherwise
+///    InvalidTakeProfitTick is returned
+///  - The order is a reduce only order for the share of the position not already committed to other reduce only orders
+///  - The close is settled by the limit orders execution timer once the tick is crossed ,repaying the position's debt through the vault
+#[ic_cdk::update(name = "placeTakeProfitOrder")]
+fn place_take_profit_order(account_index: u8, tick: Tick) -> Result<OrderId, MarketError> {
+    let user = ic_cdk::api::msg_caller();
+
+    let account = user._to_subaccount(account_index);
+
+    let Some(position) = _get_account_position(&account) else {
+        return Err(MarketError::NoPosition);
+    };
+
+    let MarketDetails { tick_spacing, .. } = _get_market_details();
+
+    let entry_tick = _compressed_tick(tick, tick_spacing);
+
+    let in_profit = if position.long {
+        entry_tick > position.entry_tick
+    } else {
+        entry_tick < position.entry_tick
+    };
+
+    if !in_profit {
+        return Err(MarketError::InvalidTakeProfitTick);
+    }
+
+    return _place_reduce_only_order(
+        user,
+        account_index,
+        CloseAmount::Fraction(100 * _ONE_PERCENT),
+        tick,
+    );
+}
+
+/// Place Reduce Only Order (internal)
+///
+/// Places a reduce only order for an account's market position ,see placeReduceOnlyOrder
+fn _place_reduce_only_order(
+    user: Principal,
+    account_index: u8,
+    close_amount: CloseAmount,
+    tick: Tick,
+) -> Result<OrderId, MarketError> {
+    let account = user._to_subaccount(account_index);
+
+    let has_pending_error =
+        ACCOUNTS_ERROR_LOGS.with_borrow(|reference| reference.contains_key(&account));
+
+    if has_pending_error {
+        return Err(MarketError::PendingErrorLog);
+    }
+
+    let Some(position) = _get_account_position(&account) else {
+        return Err(MarketError::NoPosition);
+    };
+
+    if let PositionOrderType::Limit(_) = position.order_type {
+        return Err(MarketError::NotMarketPosition);
+    }
+
+    if _get_account_limit_orders_count(&account) >= _MAX_ACCOUNT_LIMIT_ORDERS {
+        return Err(MarketError::MaxOrdersReached);
+    }
+
+    let close_share = match _close_amount_to_share(&position, close_amount) {
+        Ok(close_share) => close_share,
+        Err(error) => return Err(error),
+    };
+
+    let committed_share = _get_account_reduce_only_share(&account);
+
+    let close_share = close_share.min(position.volume_share.saturating_sub(committed_share));
+
+    if close_share == 0 {
+        return Err(MarketError::InvalidAmount);
+    }
+
+    let MarketDetails { tick_spacing, .. } = _get_market_details();
+
+    let entry_tick = _compressed_tick(tick, tick_spacing);
+
+    let close_value = _get_position_volume_value(close_share, position.long)?;
+
+    // a long position is reduced by selling its size and a short position by buying back with its value
+    let order_size = if position.long {
+        _equivalent(close_value, position.entry_tick, true)?
+    } else {
+        close_value
+    };
+
+    let Some(order) = _place_order_on_book(!position.long, order_size, entry_tick) else {
+        return Err(MarketError::CrossesBook);
+    };
+
+    let order_id = _next_limit_order_id();
+
+    _insert_account_limit_order(
+        account,
+        AccountLimitOrder {
+            owner: user,
+            account_index,
+            order_id,
+            long: !position.long,
+            collateral_value: 0,
+            debt_value: 0,
+            interest_rate: 0,
+            order,
+            timestamp: ic_cdk::api::time(),
+            reduce_only: true,
+            close_share,
+        },
+    );
+
+    return Ok(order_id);
+}
+
+/// Get Account Limit Orders
+///
+/// Returns all working limit orders of an account
+#[ic_cdk::query(name = "getAccountLimitOrders")]
+fn get_account_limit_orders(user: Principal, account_index: u8) -> Vec<AccountLimitOrder> {
+    let account = user._to_subaccount(account_index);
+
+    ACCOUNTS_LIMIT_ORDERS.with_borrow(|reference| {
+        reference
+            .range((account, 0)..=(account, OrderId::MAX))
+            .map(|(_, account_order)| account_order)
+            .collect()
+    })
+}
+
+/// Get Trades
+///
+/// Returns trades from the trade history in the order they were executed
+///
+/// Params
+///  - Start :The id of the first trade to return
+///  - Limit :The max number of trades to return ,capped at the max trades per query
+#[ic_cdk::query(name = "getTrades")]
+fn get_trades(start: TradeId, limit: u32) -> Vec<Trade> {
+    let limit = limit.min(_MAX_TRADES_PER_QUERY) as usize;
+
+    TRADES.with_borrow(|reference| {
+        reference
+            .range(start..)
+            .take(limit)
+            .map(|(_, trade)| trade)
+            .collect()
+    })
+}
+
+/// Get Account Trades
+///
+/// Returns the trades of an account in the order they were executed
+///
+/// Params
+///  - User :The owner of the account
+///  - Account Index :The index of the account
+///  - Start :The trade id to start from ,trades with a lower id are skipped
+///  - Limit :The max number of trades to return ,capped at the max trades per query
+#[ic_cdk::query(name = "getAccountTrades")]
+fn get_account_trades(
+    user: Principal,
+    account_index: u8,
+    start: TradeId,
+    limit: u32,
+) -> Vec<Trade> {
+    let account = user._to_subaccount(account_index);
+    let limit = limit.min(_MAX_TRADES_PER_QUERY) as usize;
+
+    let trade_ids: Vec<TradeId> = ACCOUNTS_TRADES.with_borrow(|reference| {
+        reference
+            .range((account, start)..=(account, TradeId::MAX))
+            .take(limit)
+            .map(|((_, trade_id), _)| trade_id)
+            .collect()
+    });
+
+    TRADES.with_borrow(|reference| {
+        trade_ids
+            .into_iter()
+            .filter_map(|trade_id| reference.get(&trade_id))
+            .collect()
+    })
+}
+
+/// Get Candles
+///
+/// Returns the OHLCV candles of an interval opened within a time range
+///
+/// Params
+///  - Interval :The candle interval
+///  - From :The start of the time range
+///  - To :The end of the time range (inclusive)
+///
+/// Note
+///  - Only intervals with at least one trade have a candle
+///  - At most the max candles per query are returned ,starting from the earliest
+#[ic_cdk::query(name = "getCandles")]
+fn get_candles(interval: CandleInterval, from: Time, to: Time) -> Vec<Candle> {
+    if from > to {
+        return Vec::new();
+    }
+
+    let duration = interval._duration();
+
+    CANDLES.with_borrow(|reference| {
+        reference
+            .range((duration, from)..=(duration, to))
+            .take(_MAX_CANDLES_PER_QUERY)
+            .map(|(_, candle)| candle)
+            .collect()
+    })
+}
+
+/// Set Position Triggers
+///
+/// Sets or clears the stop loss and take profit ticks of the caller's position
+///
+/// Params
+///  - Stop Loss Tick :The tick at which the position is closed to limit losses ,none clears it
+///  - Take Profit Tick :The tick at which the position is closed to take profit ,none clears it
+///
+/// Note
+///  - For a long position the stop loss must be below and the take profit above the entry tick and the reverse for a short position
+#[ic_cdk::update(name = "setPositionTriggers")]
+fn set_position_triggers(
+    account_index: u8,
+    stop_loss_tick: Option<Tick>,
+    take_profit_tick: Option<Tick>,
+) -> Result<(), MarketError> {
+    let user = ic_cdk::api::msg_caller();
+
+    let account = user._to_subaccount(account_index);
+
+    let Some(position) = _get_account_position(&account) else {
+        return Err(MarketError::NoPosition);
+    };
+
+    let MarketDetails { tick_spacing, .. } = _get_market_details();
+
+    let triggers = PositionTriggers::init(
+        user,
+        account_index,
+        stop_loss_tick,
+        take_profit_tick,
+        tick_spacing,
+    );
+
+    if !triggers._valid_for(position.long, position.entry_tick) {
+        return Err(MarketError::InvalidTriggers);
+    }
+
+    _set_position_triggers(account, triggers);
+    return Ok(());
+}
+
+/// Get Position Triggers
+///
+/// Returns the stop loss and take profit ticks set on an account's position
+#[ic_cdk::query(name = "getPositionTriggers")]
+fn get_position_triggers(user: Principal, account_index: u8) -> (Option<Tick>, Option<Tick>) {
+    let account = user._to_subaccount(account_index);
+
+    let MarketDetails { tick_spacing, .. } = _get_market_details();
+
+    let Some(triggers) = _get_position_triggers(&account) else {
+        return (None, None);
+    };
+
+    return (
+        triggers.stop_loss_tick.map(|tick| tick * tick_spacing),
+        triggers.take_profit_tick.map(|tick| tick * tick_spacing),
+    );
+}
+
+/// Liquidate Function
+///
+/// liquidates an account's position to avoid bad debt by checking if the current leverage exceeds the max leverage
+///
+/// Params
+///  - User :The owner of the position
+///  - Account Index :The index of the account holding the position
+///  - Reward Account Index :The index of the caller's account the liquidation fee is paid to
+///
+/// Note
+///  - Position is closed at the current tick
+///  - The caller is paid the liquidation fee out of the remaining collateral ,less the insurance fund share
+///  - If the remaining collateral is negative ,the shortfall is covered by the insurance fund first
+#[ic_cdk::update(name = "liquidatePosition")]
+fn liquidate_position(
+    user: Principal,
+    account_index: u8,
+    reward_account_index: u8,
+) -> Result<bool, MarketError> {
+    let account = user._to_subaccount(account_index);
+    let state_details = _get_state_details();
+
+    let market_details = _get_market_details();
+
+    // filled reduce only orders are settled first ,so only the rest of the position is liquidated
+    _settle_reduce_only_orders(&account);
+
+    let Some(position) = _get_account_position(&account) else {
+        return Err(MarketError::NoPosition);
+    };
+
+    let (to_liquidate, collateral_remaining, net_debt_value) =
+        _liquidation_status(position, state_details.max_leveragex10);
+
+    if to_liquidate {
+        let vault = Vault::init(market_details.vault_id);
+
+        // the position's volume is removed from the funding rate tracker before anything is settled
+        let mut funding_rate_tracker = _get_funding_rate_tracker();
+        funding_rate_tracker.remove_volume(position.volume_share, position.long)?;
+
+        let (collateral, amount_repaid) = if collateral_remaining > 0 {
+            (collateral_remaining.abs() as u128, net_debt_value)
+        } else {
+            let shortfall = collateral_remaining.abs() as u128;
+            let amount_covered = _draw_from_insurance_fund(user, account_index, shortfall);
+            (0, net_debt_value - shortfall + amount_covered)
+        };
+
+        let liquidation_fee =
+            _percentage(state_details.liquidation_fee_rate, collateral).min(collateral);
+
+        let insurance_fund_fee =
+            _percentage(state_details.insurance_fund_share, liquidation_fee).min(liquidation_fee);
+        _add_to_insurance_fund(insurance_fund_fee);
+
+        let manage_debt_params =
+            ManageDebtParams::init(position.debt_value, net_debt_value, amount_repaid);
+
+        _set_funding_rate_tracker(funding_rate_tracker);
+        vault.manage_position_update(
+            user,
+            account_index,
+            collateral - liquidation_fee,
+            manage_debt_params,
+        );
+
+        if liquidation_fee > insurance_fund_fee {
+            vault.manage_position_update(
+                ic_cdk::api::msg_caller(),
+                reward_account_index,
+                liquidation_fee - insurance_fund_fee,
+                ManageDebtParams::default(),
+            );
+        }
+
+        _remove_account_position(&account);
+        return Ok(true);
+    }
+    return Ok(false);
+}
+
+/// Get Insurance Fund Balance
+///
+/// Returns the amount available in the insurance fund for covering shortfalls
+#[ic_cdk::query(name = "getInsuranceFundBalance")]
+fn get_insurance_fund_balance() -> Amount {
+    _get_insurance_fund_balance()
+}
+
+/// Get Insurance Fund Draws
+///
+/// Returns draws made from the insurance fund to cover shortfalls in the order they were made
+///
+/// Params
+///  - Start :The id of the first draw to return
+///  - Limit :The max number of draws to return ,capped at _MAX_INSURANCE_FUND_DRAWS_PER_QUERY
+#[ic_cdk::query(name = "getInsuranceFundDraws")]
+fn get_insurance_fund_draws(start: u64, limit: u32) -> Vec<InsuranceFundDraw> {
+    let limit = limit.min(_MAX_INSURANCE_FUND_DRAWS_PER_QUERY) as usize;
+
+    INSURANCE_FUND_DRAWS.with_borrow(|reference| {
+        reference
+            .range(start..)
+            .take(limit)
+            .map(|(_, draw)| draw)
+            .collect()
+    })
+}
+
+/// Get Liquidatable Positions
+///
+/// Scans a page of positions and returns those that can be liquidated or are close to being liquidated
+///
+/// Params
+///  - Start :The account to start scanning after ,none starts from the first account
+///  - Limit :The max number of positions to scan ,capped at _MAX_LIQUIDATION_SCAN
+///  - Margin Buffer :Positions with a margin ratio within this buffer (in percentage) above the maintenance margin ratio are also returned
+///
+/// Returns
+///  - Candidates :The matching positions of the scanned page sorted by margin ratio ,lowest first
+///  - Next Start :The account to pass as start to scan the next page ,none if all positions have been scanned
+///
+/// Note
+///  - Margin ratio is the current collateral value as a percentage of the current position value
+///  - The maintenance margin ratio is the margin ratio at the max leverage
+///  - Positions are scanned in account order ,so the ordering only holds within a page ,candidates from all pages
+///    must be merged by margin ratio to rank positions across the whole market
+#[ic_cdk::query(name = "getLiquidatablePositions")]
+fn get_liquidatable_positions(
+    start: Option<Subaccount>,
+    limit: u32,
+    margin_buffer: u64,
+) -> (Vec<LiquidationCandidate>, Option<Subaccount>) {
+    let StateDetails {
+        max_leveragex10, ..
+    } = _get_state_details();
+
+    let limit = limit.min(_MAX_LIQUIDATION_SCAN) as usize;
+
+    let maintenance_margin_ratio = (10 * 100 * _ONE_PERCENT as i64) / max_leveragex10.max(1) as i64;
+
+    let positions: Vec<(Subaccount, PositionParameters)> =
+        ACCOUNTS_POSITION.with_borrow(|reference| match start {
+            Some(account) => reference
+                .range(account..)
+                .skip_while(|(key, _)| *key == account)
+                .take(limit)
+                .collect(),
+            None => reference.iter().take(limit).collect(),
+        });
+
+    let next_start = if positions.len() == limit {
+        positions.last().map(|(account, _)| *account)
+    } else {
+        None
+    };
+
+    let mut candidates: Vec<LiquidationCandidate> = Vec::new();
+
+    for (_, position) in positions {
+        if let PositionOrderType::Limit(_) = position.order_type {
+            continue;
+        }
+
+        let (liquidatable, current_collateral_value, net_debt_value) =
+            _liquidation_status(position, max_leveragex10);
+
+        let current_position_value = net_debt_value as i128 + current_collateral_value;
+
+        let margin_ratio = if current_position_value > 0 {
+            ((current_collateral_value * 100 * _ONE_PERCENT as i128) / current_position_value)
+                as i64
+        } else {
+            i64::MIN
+        };
+
+        if !liquidatable && margin_ratio > maintenance_margin_ratio + margin_buffer as i64 {
+            continue;
+        }
+
+        candidates.push(LiquidationCandidate {
+            owner: position.owner,
+            account_index: position.account_index,
+            position,
+            margin_ratio,
+            liquidatable,
+        });
+    }
+
+    candidates.sort_by_key(|candidate| candidate.margin_ratio);
+
+    return (candidates, next_start);
+}
+
+/// Quote Market Order
+///
+/// Previews the result of opening a market position without changing the state of the market
+///
+/// Params
+///  - Long :true for a long position and false for a short position
+///  - Collateral Value :The amount in collateral token to utilise as collateral
+///  - Leverage :The leverage multiplied by 10
+///  - Max Tick :max executing tick ,if set to none the default max tick is used
+///
+/// Returns
+///  - Quote :The expected fill ,price impact ,unused collateral and estimated liquidation price
+///
+/// Note
+///  - The quote is only valid for the current state of the book ,it does not account for the caller's balance or the vault's free liquidity
+#[ic_cdk::query(name = "quoteMarketOrder")]
+fn quote_market_order(
+    long: bool,
+    collateral_value: Amount,
+    leveragex10: u8,
+    max_tick: Option<Tick>,
+) -> Result<MarketOrderQuote, MarketError> {
+    let StateDetails {
+        max_leveragex10,
+        min_collateral,
+        not_paused,
+        taker_fee_rate,
+        ..
+    } = _get_state_details();
+
+    if !not_paused {
+        return Err(MarketError::Paused);
+    }
+
+    if leveragex10 < 10 {
+        return Err(MarketError::InvalidLeverage);
+    }
+
+    if leveragex10 >= max_leveragex10 {
+        return Err(MarketError::LeverageTooHigh);
+    }
+
+    if collateral_value < min_collateral {
+        return Err(MarketError::CollateralTooSmall);
+    }
+
+    let debt_value = (u128::from(leveragex10 - 10) * collateral_value) / 10;
+
+    let MarketDetails { tick_spacing, .. } = _get_market_details();
+
+    let (base_amount, quote_amount, amount_remaining_value, resulting_tick, crossed_ticks);
+
+    if long {
+        let lowest_sell_offer_tick = _get_lowest_sell_offer_tick();
+
+        let stopping_tick = max_tick
+            .and_then(|x| Some(_compressed_tick(x, tick_spacing)))
+            .unwrap_or(_def_max_tick(lowest_sell_offer_tick, true));
+
+        let amount_remaining;
+
+        (base_amount, amount_remaining, resulting_tick, crossed_ticks) = TICKS_DETAILS
+            .with_borrow(|ticks_details| {
+                INTEGRAL_BITMAPS.with_borrow(|integrals_bitmaps| {
+                    _simulate_swap(
+                        true,
+                        lowest_sell_offer_tick,
+                        stopping_tick,
+                        collateral_value + debt_value,
+                        integrals_bitmaps,
+                        ticks_details,
+                    )
+                })
+            })
+            .map_err(|_| MarketError::CalculationError)?;
+
+        quote_amount = collateral_value + debt_value - amount_remaining;
+        amount_remaining_value = amount_remaining;
+    } else {
+        let highest_buy_offer_tick = _get_highest_buy_offer_tick();
+
+        let stopping_tick = max_tick
+            .and_then(|x| Some(_compressed_tick(x, tick_spacing)))
+            .unwrap_or(_def_max_tick(highest_buy_offer_tick, false));
+
+        let Some(starting_tick) =
+            _get_next_best_offer_tick(false, highest_buy_offer_tick, stopping_tick)
+        else {
+            return Err(MarketError::NoLiquidity);
+        };
+
+        let order_size = _equivalent(collateral_value, starting_tick, true)?
+            + _equivalent(debt_value, starting_tick, true)?;
+
+        let amount_remaining;
+
+        (
+            quote_amount,
+            amount_remaining,
+            resulting_tick,
+            crossed_ticks,
+        ) = TICKS_DETAILS
+            .with_borrow(|ticks_details| {
+                INTEGRAL_BITMAPS.with_borrow(|integrals_bitmaps| {
+                    _simulate_swap(
+                        false,
+                        starting_tick,
+                        stopping_tick,
+                        order_size,
+                        integrals_bitmaps,
+                        ticks_details,
+                    )
+                })
+            })
+            .map_err(|_| MarketError::CalculationError)?;
+
+        base_amount = order_size - amount_remaining;
+        amount_remaining_value = _equivalent(amount_remaining, starting_tick, false)?;
+    }
+
+    if base_amount == 0 || quote_amount == 0 {
+        return Err(MarketError::NoLiquidity);
+    }
+
+    // the unfilled amount is taken off the debt first
+    let (un_used_debt_value, un_used_collateral_value) = if amount_remaining_value >= debt_value {
+        (debt_value, amount_remaining_value - debt_value)
+    } else {
+        (amount_remaining_value, 0)
+    };
+
+    let resulting_debt_value = debt_value - un_used_debt_value;
+    let resulting_collateral_value = collateral_value - un_used_collateral_value;
+
+    let taker_fee = _percentage(taker_fee_rate, quote_amount).min(resulting_collateral_value);
+
+    let liquidation_tick = _estimated_liquidation_tick(
+        long,
+        resulting_tick,
+        resulting_collateral_value - taker_fee,
+        resulting_debt_value,
+        max_leveragex10,
+    );
+
+    return Ok(MarketOrderQuote {
+        base_amount,
+        quote_amount,
+        average_price: ((quote_amount * _PRICE_FACTOR) / base_amount) as Tick * tick_spacing,
+        resulting_tick: resulting_tick * tick_spacing,
+        crossed_ticks: crossed_ticks
+            .into_iter()
+            .map(|tick| tick * tick_spacing)
+            .collect(),
+        unused_collateral: un_used_collateral_value,
+        taker_fee,
+        liquidation_price: liquidation_tick * tick_spacing,
+    });
+}
+
+/// Estimated Liquidation Tick
+///
+/// Estimates the tick at which a market position would become liquidatable ,ignoring interest and funding
+///
+/// Note
+///  - A position is liquidatable once its current leverage reaches the max leverage (see _liquidation_status)
+///  ,i.e once the value of the position falls to debt * max leverage / (max leverage - 1)
+fn _estimated_liquidation_tick(
+    long: bool,
+    entry_tick: Tick,
+    collateral_value: Amount,
+    debt_value: Amount,
+    max_leveragex10: u8,
+) -> Tick {
+    let position_value = collateral_value + debt_value;
+
+    if position_value == 0 {
+        return entry_tick;
+    }
+
+    // value of the position relative to its initial value at liquidation
+    let liquidation_value = (debt_value * u128::from(max_leveragex10))
+        / u128::from(max_leveragex10.saturating_sub(10).max(1));
+
+    if long {
+        return ((u128::from(entry_tick) * liquidation_value) / position_value) as Tick;
+    } else {
+        let liquidation_ratio = (2 * position_value).saturating_sub(liquidation_value);
+        return ((u128::from(entry_tick) * liquidation_ratio) / position_value) as Tick;
+    }
 }
 
 async fn _open_position_checks(
@@ -505,13 +1980,33 @@ async fn _open_position_checks(
     vault: Vault,
     collateral_value: Amount,
     leveragex10: u8,
-) -> Result<u32, &'static str> {
-    let failed_initial_check = _has_position_or_pending_error_log(&account);
+) -> Result<u32, MarketError> {
+    let has_pending_error =
+        ACCOUNTS_ERROR_LOGS.with_borrow(|reference| reference.contains_key(&account));
 
-    if failed_initial_check {
-        return Err("Account has pending error or unclosed position");
+    if has_pending_error {
+        return Err(MarketError::PendingErrorLog);
     }
 
+    if _get_account_position(&account).is_some() {
+        return Err(MarketError::PositionExists);
+    }
+
+    _collateral_and_debt_checks(user, vault, collateral_value, leveragex10).await
+}
+
+/// Collateral And Debt Checks
+///
+/// Checks that the market is not paused ,the leverage and collateral are within limits and the vault can provide the debt
+///
+/// Returns
+///  - Interest Rate :The interest rate on the debt
+async fn _collateral_and_debt_checks(
+    user: Principal,
+    vault: Vault,
+    collateral_value: Amount,
+    leveragex10: u8,
+) -> Result<u32, MarketError> {
     let StateDetails {
         max_leveragex10,
         min_collateral,
@@ -520,13 +2015,22 @@ async fn _open_position_checks(
     } = _get_state_details();
 
     if !not_paused {
-        return Err("Market is paused");
+        return Err(MarketError::Paused);
+    }
+
+    // levarage is always given as a multiple of ten and can not be less than 1x
+    if leveragex10 < 10 {
+        return Err(MarketError::InvalidLeverage);
     }
 
     // if leverage is greater than max leverage or collateral value is less than min collateral
     //returns
-    if leveragex10 >= max_leveragex10 || collateral_value < min_collateral {
-        return Err("Max leverage exceeded or collateral is too small");
+    if leveragex10 >= max_leveragex10 {
+        return Err(MarketError::LeverageTooHigh);
+    }
+
+    if collateral_value < min_collateral {
+        return Err(MarketError::CollateralTooSmall);
     }
 
     // levarage is always given as a multiple of ten
@@ -538,37 +2042,280 @@ async fn _open_position_checks(
         .await;
 
     if !valid {
-        return Err("Not enough liquidity for debt");
+        return Err(MarketError::InsufficientVaultLiquidity);
     }
     return Ok(interest_rate);
 }
 
-fn _open_limit_short_position(
-    _account: Subaccount,
-    _collateral_value: Amount,
-    _debt_value: Amount,
-    _interest_rate: u32,
-    _entry_tick: Tick,
-) -> Option<(PositionParameters, Vec<Tick>)> {
-    let equivalent = |amount: Amount, tick: Tick, buy: bool| -> Amount {
-        //  let tick_price = _tick_to_price(tick);
-        _equivalent(amount, tick, buy)
+/// Add Position Margin
+///
+/// Collects the margin from the user through the vault and repays the position debt with it ,any amount above
+/// the debt is kept as extra margin
+///
+/// Note:The accrued interest on the debt is added to the debt before repaying
+async fn _add_position_margin(
+    user: Principal,
+    account_index: u8,
+    amount: Amount,
+) -> Result<PositionParameters, MarketError> {
+    let account = user._to_subaccount(account_index);
+
+    let Some(position) = _get_account_position(&account) else {
+        return Err(MarketError::NoPosition);
     };
 
-    let highest_buy_offer_tick = _get_highest_buy_offer_tick();
+    if let PositionOrderType::Limit(_) = position.order_type {
+        return Err(MarketError::NotMarketPosition);
+    }
 
-    if highest_buy_offer_tick != 0 && _entry_tick <= highest_buy_offer_tick {
-        return None;
+    if amount == 0 {
+        return Err(MarketError::InvalidAmount);
     }
 
-    let (collateral, debt) = (
-        equivalent(_collateral_value, _entry_tick, true),
-        equivalent(_debt_value, _entry_tick, true),
+    let market_details = _get_market_details();
+
+    let vault = Vault::init(market_details.vault_id);
+
+    // Checks that user has sufficient balance and reduces it by the margin
+    let (valid, _) = vault.create_position_validity_check(user, amount, 0).await;
+
+    if !valid {
+        return Err(MarketError::InsufficientBalance);
+    }
+
+    // position could have been closed or liquidated while awaiting the vault
+    let Some(mut position) = _get_account_position(&account) else {
+        vault.manage_position_update(user, account_index, amount, ManageDebtParams::default());
+        return Err(MarketError::NoPosition);
+    };
+
+    let mut manage_debt_params = _accrue_position_interest(&mut position);
+
+    let amount_repaid = amount.min(position.debt_value);
+
+    position.debt_value -= amount_repaid;
+    position.collateral_value += amount_repaid;
+    position.extra_margin += amount - amount_repaid;
+
+    _insert_account_position(account, position);
+
+    manage_debt_params.amount_repaid = amount_repaid;
+
+    if manage_debt_params.amount_repaid != 0
+        || manage_debt_params.net_debt != manage_debt_params.initial_debt
+    {
+        vault.manage_position_update(user, account_index, 0, manage_debt_params);
+    }
+
+    return Ok(position);
+}
+
+/// Remove Position Margin
+///
+/// Withdraws the margin from the position's extra margin first and borrows the rest from the vault as extra debt
+/// on the position ,then sends it to the user
+///
+/// Note:The accrued interest on the debt is added to the debt and the interest rate becomes the debt weighted
+/// average of the current rate and the rate for the new debt
+async fn _remove_position_margin(
+    user: Principal,
+    account_index: u8,
+    amount: Amount,
+) -> Result<PositionParameters, MarketError> {
+    let account = user._to_subaccount(account_index);
+
+    let Some(position) = _get_account_position(&account) else {
+        return Err(MarketError::NoPosition);
+    };
+
+    if let PositionOrderType::Limit(_) = position.order_type {
+        return Err(MarketError::NotMarketPosition);
+    }
+
+    if let Err(error) = _margin_removal_checks(position, amount) {
+        return Err(error);
+    }
+
+    let market_details = _get_market_details();
+
+    let vault = Vault::init(market_details.vault_id);
+
+    // extra margin is withdrawn first and only the rest is borrowed
+    let removed_extra_margin = amount.min(position.extra_margin);
+
+    let borrowed_amount = amount - removed_extra_margin;
+
+    let mut interest_rate = 0;
+
+    if borrowed_amount != 0 {
+        // Checks that vault contains free liquidity greater or equal to amount and then calculate interest rate
+        let valid;
+        (valid, interest_rate) = vault
+            .create_position_validity_check(user, 0, borrowed_amount)
+            .await;
+
+        if !valid {
+            return Err(MarketError::InsufficientVaultLiquidity);
+        }
+    }
+
+    // position could have changed while awaiting the vault
+    let checked_position = match _get_account_position(&account) {
+        Some(position) if position.extra_margin < removed_extra_margin => {
+            Err(MarketError::InvalidAmount)
+        }
+        Some(position) => _margin_removal_checks(position, amount).map(|_| position),
+        None => Err(MarketError::NoPosition),
+    };
+
+    let mut position = match checked_position {
+        Ok(position) => position,
+        Err(error) => {
+            if borrowed_amount != 0 {
+                vault.manage_position_update(
+                    user,
+                    account_index,
+                    0,
+                    ManageDebtParams::init(borrowed_amount, borrowed_amount, borrowed_amount),
+                );
+            }
+            return Err(error);
+        }
+    };
+
+    let manage_debt_params = _accrue_position_interest(&mut position);
+
+    let new_debt_value = position.debt_value + borrowed_amount;
+
+    position.interest_rate = (u128::from(position.interest_rate) * position.debt_value
+        + u128::from(interest_rate) * borrowed_amount)
+        .checked_div(new_debt_value)
+        .map_or(position.interest_rate, |rate| rate as u32);
+
+    position.debt_value = new_debt_value;
+    position.collateral_value -= borrowed_amount;
+    position.extra_margin -= removed_extra_margin;
+
+    _insert_account_position(account, position);
+
+    vault.manage_position_update(user, account_index, amount, manage_debt_params);
+
+    return Ok(position);
+}
+
+/// Margin Removal Checks
+///
+/// Checks that the remaining collateral is not less than the min collateral and the resulting leverage is less than the max leverage
+fn _margin_removal_checks(position: PositionParameters, amount: Amount) -> Result<(), MarketError> {
+    let StateDetails {
+        max_leveragex10,
+        min_collateral,
+        not_paused,
+        ..
+    } = _get_state_details();
+
+    if !not_paused {
+        return Err(MarketError::Paused);
+    }
+
+    let total_collateral_value = position.collateral_value + position.extra_margin;
+
+    if amount == 0
+        || amount >= total_collateral_value
+        || total_collateral_value - amount < min_collateral
+    {
+        return Err(MarketError::CollateralTooSmall);
+    }
+
+    let (to_liquidate, current_collateral_value, net_debt_value) =
+        _liquidation_status(position, max_leveragex10);
+
+    if to_liquidate || current_collateral_value <= amount as i128 {
+        return Err(MarketError::LeverageTooHigh);
+    }
+
+    let resulting_leverage_x10 = ((net_debt_value as i128 + current_collateral_value) * 10)
+        / (current_collateral_value - amount as i128);
+
+    if resulting_leverage_x10 >= max_leveragex10 as i128 {
+        return Err(MarketError::LeverageTooHigh);
+    }
+    return Ok(());
+}
+
+/// Accrue Position Interest
+///
+/// Adds the interest accrued on a position's debt to the debt and restarts the interest period
+///
+/// Returns
+///  - Manage Debt Params :for telling the vault about the accrued interest ,nothing is repaid
+fn _accrue_position_interest(position: &mut PositionParameters) -> ManageDebtParams {
+    let initial_debt_value = position.debt_value;
+
+    let interest_value = _calc_interest(
+        position.debt_value,
+        position.interest_rate,
+        position.timestamp,
+        &IcClock,
     );
 
-    let mut order = LimitOrder::new(collateral + debt, _entry_tick, false);
+    position.debt_value += interest_value;
+    position.timestamp = ic_cdk::api::time();
+
+    ManageDebtParams::init(initial_debt_value, position.debt_value, 0)
+}
+
+/// Close Market Position
+///
+/// Closes a market position either fully or partially (see Close Long PositionDetails) and settles the
+/// removed collateral and debt repayment with the vault
+///
+/// Returns
+///  - Collateral Value :The amount sent back to the position owner
+fn _close_market_position(
+    user: Principal,
+    account_index: u8,
+    position: &mut PositionParameters,
+    max_tick: Option<Tick>,
+    vault: Vault,
+) -> Result<Amount, MarketError> {
+    let account = user._to_subaccount(account_index);
+
+    // filled reduce only orders are settled first ,so the close only closes the rest of the position
+    _settle_reduce_only_orders(&account);
+
+    let Some(settled_position) = _get_account_position(&account) else {
+        return Ok(0);
+    };
+    *position = settled_position;
+
+    let closing = if position.long {
+        _close_market_long_position(account, position, max_tick)
+    } else {
+        _close_market_short_position(account, position, max_tick)
+    };
+
+    let (collateral_value, crossed_ticks, manage_debt_params) = closing?;
+
+    _schedule_execution_for_ticks_orders(crossed_ticks);
+
+    if collateral_value != 0 || manage_debt_params.amount_repaid != 0 {
+        vault.manage_position_update(user, account_index, collateral_value, manage_debt_params);
+    }
+
+    return Ok(collateral_value);
+}
+
+fn _open_limit_short_position(
+    _account: Subaccount,
+    account_index: u8,
+    _collateral_value: Amount,
+    _debt_value: Amount,
+    _interest_rate: u32,
+    _entry_tick: Tick,
+) -> Result<(PositionParameters, Vec<Tick>), MarketError> {
+    let order = _open_limit_order(false, _collateral_value, _debt_value, _entry_tick)?;
 
-    _open_order(&mut order);
     let owner = ic_cdk::api::msg_caller();
 
     let position = PositionParameters {
@@ -581,42 +2328,24 @@ fn _open_limit_short_position(
         volume_share: 0, // not initialised yet
         order_type: PositionOrderType::Limit(order),
         timestamp: 0, //not initialised
+        extra_margin: 0,
+        account_index,
     };
 
     _insert_account_position(_account, position);
 
-    let lowest_sell_offer_tick = _get_lowest_sell_offer_tick();
-    if lowest_sell_offer_tick == 0 {
-        _update_lowest_sell_offer_tick(_entry_tick);
-    } else {
-        let active_lowest_sell_offer_tick =
-            _get_next_best_offer_tick(true, lowest_sell_offer_tick, _entry_tick);
-        if active_lowest_sell_offer_tick.unwrap_or(_entry_tick) == _entry_tick {
-            _update_lowest_sell_offer_tick(_entry_tick);
-        }
-    }
-
-    return Some((position, Vec::new()));
+    return Ok((position, Vec::new()));
 }
 
 fn _open_limit_long_position(
     _account: Subaccount,
+    account_index: u8,
     _collateral_value: Amount,
     _debt_value: Amount,
     _interest_rate: u32,
-    _entry_tick: Tick,
-) -> Option<(PositionParameters, Vec<Tick>)> {
-    let lowest_sell_offer_tick = _get_lowest_sell_offer_tick();
-
-    if lowest_sell_offer_tick != 0 && _entry_tick >= lowest_sell_offer_tick {
-        return None;
-    }
-
-    let (collateral, debt) = (_collateral_value, _debt_value);
-
-    let mut order = LimitOrder::new(collateral + debt, _entry_tick, true);
-
-    _open_order(&mut order);
+    _entry_tick: Tick,
+) -> Result<(PositionParameters, Vec<Tick>), MarketError> {
+    let order = _open_limit_order(true, _collateral_value, _debt_value, _entry_tick)?;
 
     let owner = ic_cdk::api::msg_caller();
 
@@ -630,22 +2359,156 @@ fn _open_limit_long_position(
         volume_share: 0, // not initialised yet
         order_type: PositionOrderType::Limit(order),
         timestamp: 0, //not initialised
+        extra_margin: 0,
+        account_index,
     };
     _insert_account_position(_account, position);
 
-    let highest_buy_offer_tick = _get_highest_buy_offer_tick();
+    return Ok((position, Vec::new()));
+}
+
+/// Crosses Book
+///
+/// Checks if a limit order at the entry tick would be executed immediately against the opposite side of the book
+fn _crosses_book(long: bool, entry_tick: Tick) -> bool {
+    if long {
+        let lowest_sell_offer_tick = _get_lowest_sell_offer_tick();
+        return lowest_sell_offer_tick != 0 && entry_tick >= lowest_sell_offer_tick;
+    } else {
+        let highest_buy_offer_tick = _get_highest_buy_offer_tick();
+        return highest_buy_offer_tick != 0 && entry_tick <= highest_buy_offer_tick;
+    }
+}
+
+/// Simulated Swap
+///
+/// Calculates the outcome of a swap without modifying the book ,so that any calculation on the outcome
+/// can be made before the swap is executed
+///
+/// Params
+///  - Buy :true for a buy order and false for a sell order
+///  - Starting Tick :The tick the swap starts from
+///  - Stopping Tick :The max tick
+///  - Order Size :The size of the order ,in the quote asset for a buy order and in the base asset for a sell order
+///
+/// Returns
+///  - Amount Out ,Amount Remaining ,Resulting Tick and Crossed Ticks as returned by the swap (see Swap function)
+///  - or CalculationError if the swap can not be calculated
+fn _simulated_swap(
+    buy: bool,
+    starting_tick: Tick,
+    stopping_tick: Tick,
+    order_size: Amount,
+) -> Result<(Amount, Amount, Tick, Vec<Tick>), MarketError> {
+    let outcome = TICKS_DETAILS.with_borrow(|ticks_details| {
+        INTEGRAL_BITMAPS.with_borrow(|integrals_bitmaps| {
+            _simulate_swap(
+                buy,
+                starting_tick,
+                stopping_tick,
+                order_size,
+                integrals_bitmaps,
+                ticks_details,
+            )
+        })
+    })?;
+
+    return Ok(outcome);
+}
+
+/// Open Limit Order
+///
+/// Places a limit order at the entry tick and updates the best offer on that side of the book
+///
+/// Params
+///  - Long :true for a buy order and false for a sell order
+///  - Collateral Value :The collateral value of the order
+///  - Debt Value :The debt value of the order
+///  - Entry Tick :The reference tick of the order
+///
+/// Returns
+///  - Order :The placed order
+///  - or CrossesBook if the order would cross the book
+fn _open_limit_order(
+    long: bool,
+    collateral_value: Amount,
+    debt_value: Amount,
+    entry_tick: Tick,
+) -> Result<LimitOrder, MarketError> {
+    let order_size = _limit_order_size(long, collateral_value, debt_value, entry_tick)?;
+
+    return _place_order_on_book(long, order_size, entry_tick).ok_or(MarketError::CrossesBook);
+}
+
+/// Limit Order Size
+///
+/// Returns the size of a limit order ,in quote asset for a buy order and in base asset for a sell order
+///
+/// Params
+///  - Long :true for a buy order and false for a sell order
+///  - Collateral Value :The collateral value of the order
+///  - Debt Value :The debt value of the order
+///  - Entry Tick :The reference tick of the order
+fn _limit_order_size(
+    long: bool,
+    collateral_value: Amount,
+    debt_value: Amount,
+    entry_tick: Tick,
+) -> Result<Amount, MarketError> {
+    if long {
+        return Ok(collateral_value + debt_value);
+    }
 
-    if highest_buy_offer_tick == 0 {
-        _update_highest_buy_offer_tick(_entry_tick);
+    return Ok(_equivalent(collateral_value, entry_tick, true)?
+        + _equivalent(debt_value, entry_tick, true)?);
+}
+
+/// Place Order On Book
+///
+/// Places a limit order of a particular size at the entry tick and updates the best offer on that side of the book
+///
+/// Params
+///  - Buy :true for a buy order and false for a sell order
+///  - Order Size :The size of the order ,in quote asset for a buy order and in base asset for a sell order
+///  - Entry Tick :The reference tick of the order
+///
+/// Returns
+///  - Order :The placed order or none if the order would cross the book
+fn _place_order_on_book(buy: bool, order_size: Amount, entry_tick: Tick) -> Option<LimitOrder> {
+    if _crosses_book(buy, entry_tick) {
+        return None;
+    }
+
+    let mut order = LimitOrder::new(order_size, entry_tick, buy);
+
+    _open_order(&mut order);
+
+    if buy {
+        let highest_buy_offer_tick = _get_highest_buy_offer_tick();
+
+        if highest_buy_offer_tick == 0 {
+            _update_highest_buy_offer_tick(entry_tick);
+        } else {
+            let active_highest_buy_offer_tick =
+                _get_next_best_offer_tick(false, highest_buy_offer_tick, entry_tick);
+            if active_highest_buy_offer_tick.unwrap_or(entry_tick) == entry_tick {
+                _update_highest_buy_offer_tick(entry_tick);
+            }
+        }
     } else {
-        let active_highest_buy_offer_tick =
-            _get_next_best_offer_tick(false, highest_buy_offer_tick, _entry_tick);
-        if active_highest_buy_offer_tick.unwrap_or(_entry_tick) == _entry_tick {
-            _update_highest_buy_offer_tick(_entry_tick);
+        let lowest_sell_offer_tick = _get_lowest_sell_offer_tick();
+        if lowest_sell_offer_tick == 0 {
+            _update_lowest_sell_offer_tick(entry_tick);
+        } else {
+            let active_lowest_sell_offer_tick =
+                _get_next_best_offer_tick(true, lowest_sell_offer_tick, entry_tick);
+            if active_lowest_sell_offer_tick.unwrap_or(entry_tick) == entry_tick {
+                _update_lowest_sell_offer_tick(entry_tick);
+            }
         }
     }
 
-    return Some((position, Vec::new()));
+    return Some(order);
 }
 
 /// Open Market Long Position'
@@ -653,11 +2516,13 @@ fn _open_limit_long_position(
 /// Params :See Open Position for params definition
 fn _open_market_long_position(
     account: Subaccount,
+    account_index: u8,
     collateral_value: Amount,
     debt_value: Amount,
     interest_rate: u32,
     max_tick: Option<Tick>,
-) -> Option<(PositionParameters, Vec<Tick>)> {
+    fill_or_kill: bool,
+) -> Result<(PositionParameters, Vec<Tick>, Amount), MarketError> {
     let (collateral, debt) = (collateral_value, debt_value);
 
     let lowest_sell_offer_tick = _get_lowest_sell_offer_tick();
@@ -668,12 +2533,17 @@ fn _open_market_long_position(
         .and_then(|x| Some(_compressed_tick(x, tick_spacing)))
         .unwrap_or(_def_max_tick(lowest_sell_offer_tick, true));
 
-    let (_amount_out, amount_remaining_value, resulting_tick, crossed_ticks) = _swap(
-        collateral + debt,
+    // the swap is simulated first ,so every calculation on its outcome is made before the book is modified
+    let (_, amount_remaining_value, _, _) = _simulated_swap(
         true,
         lowest_sell_offer_tick,
         stopping_tick,
-    );
+        collateral + debt,
+    )?;
+
+    if fill_or_kill && amount_remaining_value != 0 {
+        return Err(MarketError::NotFullyFilled);
+    }
 
     let (un_used_debt_value, un_used_collateral_value) = if amount_remaining_value >= debt_value {
         (debt_value, amount_remaining_value - debt_value)
@@ -686,7 +2556,21 @@ fn _open_market_long_position(
 
     let position_value = collateral_value + debt_value - amount_remaining_value;
 
-    let volume_share = _calc_position_volume_share(position_value, true);
+    let taker_fee = _calc_trading_fee(position_value, false, resulting_collateral_value);
+
+    let mut funding_rate_tracker = _get_funding_rate_tracker();
+    let volume_share = funding_rate_tracker.add_volume(position_value - taker_fee, true)?;
+
+    let (_, _, resulting_tick, crossed_ticks) = _swap(
+        account,
+        collateral + debt,
+        true,
+        lowest_sell_offer_tick,
+        stopping_tick,
+    )?;
+
+    _set_funding_rate_tracker(funding_rate_tracker);
+    _accrue_trading_fee(taker_fee);
 
     let owner = ic_cdk::api::msg_caller();
 
@@ -694,12 +2578,14 @@ fn _open_market_long_position(
         owner,
         long: true,
         entry_tick: resulting_tick,
-        collateral_value: resulting_collateral_value,
+        collateral_value: resulting_collateral_value - taker_fee,
         debt_value: resulting_debt_value, //actual debt
         interest_rate,
         volume_share,
         order_type: PositionOrderType::Market,
         timestamp: ic_cdk::api::time(),
+        extra_margin: 0,
+        account_index,
     };
 
     if resulting_tick > lowest_sell_offer_tick || crossed_ticks.contains(&lowest_sell_offer_tick) {
@@ -711,7 +2597,7 @@ fn _open_market_long_position(
     }
     _insert_account_position(account, position);
 
-    return Some((position, crossed_ticks));
+    return Ok((position, crossed_ticks, taker_fee));
 }
 
 /// Open Market Short Position
@@ -719,16 +2605,13 @@ fn _open_market_long_position(
 /// Similar to Open Market Long position but for opening short positions
 fn _open_market_short_position(
     account: Subaccount,
+    account_index: u8,
     collateral_value: Amount,
     debt_value: Amount,
     interest_rate: u32,
     max_tick: Option<Tick>,
-) -> Option<(PositionParameters, Vec<Tick>)> {
-    let equivalent = |amount: Amount, tick: Tick, buy: bool| -> Amount {
-        //  let tick_price = _tick_to_price(tick);
-        _equivalent(amount, tick, buy)
-    };
-
+    fill_or_kill: bool,
+) -> Result<(PositionParameters, Vec<Tick>, Amount), MarketError> {
     let highest_buy_offer_tick = _get_highest_buy_offer_tick();
 
     let MarketDetails { tick_spacing, .. } = _get_market_details();
@@ -739,18 +2622,23 @@ fn _open_market_short_position(
     let starting_tick =
         match _get_next_best_offer_tick(false, highest_buy_offer_tick, stopping_tick) {
             Some(tick) => tick,
-            None => return None,
+            None => return Err(MarketError::NoLiquidity),
         };
 
     let (collateral, debt) = (
-        equivalent(collateral_value, starting_tick, true),
-        equivalent(debt_value, starting_tick, true),
+        _equivalent(collateral_value, starting_tick, true)?,
+        _equivalent(debt_value, starting_tick, true)?,
     );
 
-    let (amount_out_value, amount_remaining, resulting_tick, crossed_ticks) =
-        _swap(collateral + debt, false, starting_tick, stopping_tick);
+    // the swap is simulated first ,so every calculation on its outcome is made before the book is modified
+    let (amount_out_value, amount_remaining, _, _) =
+        _simulated_swap(false, starting_tick, stopping_tick, collateral + debt)?;
+
+    if fill_or_kill && amount_remaining != 0 {
+        return Err(MarketError::NotFullyFilled);
+    }
 
-    let amount_remaining_value = equivalent(amount_remaining, starting_tick, false);
+    let amount_remaining_value = _equivalent(amount_remaining, starting_tick, false)?;
 
     let (un_used_debt_value, un_used_collateral_value) = if amount_remaining_value >= debt_value {
         (debt_value, amount_remaining_value - debt_value)
@@ -763,7 +2651,21 @@ fn _open_market_short_position(
 
     let position_value = amount_out_value;
 
-    let volume_share = _calc_position_volume_share(position_value, false);
+    let taker_fee = _calc_trading_fee(position_value, false, resulting_collateral_value);
+
+    let mut funding_rate_tracker = _get_funding_rate_tracker();
+    let volume_share = funding_rate_tracker.add_volume(position_value - taker_fee, false)?;
+
+    let (_, _, resulting_tick, crossed_ticks) = _swap(
+        account,
+        collateral + debt,
+        false,
+        starting_tick,
+        stopping_tick,
+    )?;
+
+    _set_funding_rate_tracker(funding_rate_tracker);
+    _accrue_trading_fee(taker_fee);
 
     let owner = ic_cdk::api::msg_caller();
 
@@ -771,12 +2673,14 @@ fn _open_market_short_position(
         owner,
         long: false,
         entry_tick: resulting_tick,
-        collateral_value: resulting_collateral_value,
+        collateral_value: resulting_collateral_value - taker_fee,
         debt_value: resulting_debt_value, //actual debt
         interest_rate,
         volume_share,
         order_type: PositionOrderType::Market,
         timestamp: ic_cdk::api::time(),
+        extra_margin: 0,
+        account_index,
     };
 
     if resulting_tick < starting_tick || crossed_ticks.contains(&starting_tick) {
@@ -787,7 +2691,7 @@ fn _open_market_short_position(
     }
     _insert_account_position(account, position);
 
-    return Some((position, crossed_ticks));
+    return Ok((position, crossed_ticks, taker_fee));
 }
 
 /// Close Long PositionDetails
@@ -805,6 +2709,7 @@ fn _open_market_short_position(
 ///  - Current Collateral :The amount to send to position owner after paying debt ,this amount is zero if debt is not fully paid
 ///  - Resulting Tick :The resulting tick from swapping
 ///  - Crosssed Ticks :An array of ticks that have been crossed during swapping
+///  - or NoLiquidity if there is no offer to close against
 ///   
 /// Note
 ///  - If position can not be closed fully ,the position is partially closed (updated) and debt is paid back either fully or partially
@@ -812,14 +2717,8 @@ fn _close_market_long_position(
     account: Subaccount,
     position: &mut PositionParameters,
     max_tick: Option<Tick>,
-) -> (Amount, Vec<Tick>, ManageDebtParams) {
-    //  let entry_price = _tick_to_price(position.entry_tick);
-    let equivalent_at_entry_price =
-        |amount: Amount, buy: bool| -> Amount { _equivalent(amount, position.entry_tick, buy) };
-
-    let position_realised_value = _calc_position_realised_value(position.volume_share, true);
-
-    let realised_position_size = equivalent_at_entry_price(position_realised_value, true);
+) -> Result<(Amount, Vec<Tick>, ManageDebtParams), MarketError> {
+    let entry_tick = position.entry_tick;
 
     let highest_buy_offer_tick = _get_highest_buy_offer_tick();
 
@@ -831,20 +2730,47 @@ fn _close_market_long_position(
     let Some(starting_tick) =
         _get_next_best_offer_tick(false, highest_buy_offer_tick, stopping_tick)
     else {
-        return (
-            0,
-            Vec::new(),
-            ManageDebtParams::init(position.debt_value, position.debt_value, 0),
-        );
+        return Err(MarketError::NoLiquidity);
+    };
+
+    let position_value = _get_position_volume_value(position.volume_share, true)?;
+
+    let realised_position_size = _equivalent(position_value, entry_tick, true)?;
+
+    // the swap is simulated first ,so every calculation on its outcome is made before the book is modified
+    let (amount_out_value, amount_remaining, _, _) =
+        _simulated_swap(false, starting_tick, stopping_tick, realised_position_size)?;
+
+    let amount_remaining_value = _equivalent(amount_remaining, entry_tick, false)?;
+
+    // the position's volume is removed from the funding rate tracker and any unfilled amount added back
+    let mut funding_rate_tracker = _get_funding_rate_tracker();
+    funding_rate_tracker.remove_volume(position.volume_share, true)?;
+    let volume_share = if amount_remaining > 0 {
+        funding_rate_tracker.add_volume(amount_remaining_value, true)?
+    } else {
+        0
     };
 
-    let (amount_out_value, amount_remaining, resulting_tick, crossed_ticks) =
-        _swap(realised_position_size, false, starting_tick, stopping_tick);
+    let (_, _, resulting_tick, crossed_ticks) = _swap(
+        account,
+        realised_position_size,
+        false,
+        starting_tick,
+        stopping_tick,
+    )?;
+
+    _set_funding_rate_tracker(funding_rate_tracker);
+
+    // the taker fee is taken from the swap proceeds before the debt is settled
+    let taker_fee = _charge_trading_fee(amount_out_value, false, amount_out_value);
+    let amount_out_value = amount_out_value - taker_fee;
 
     let interest_value = _calc_interest(
         position.debt_value,
         position.interest_rate,
         position.timestamp,
+        &IcClock,
     );
 
     let profit: u128;
@@ -852,21 +2778,21 @@ fn _close_market_long_position(
     let manage_debt_params: ManageDebtParams;
 
     if amount_remaining > 0 {
-        let amount_remaining_value = equivalent_at_entry_price(amount_remaining, false);
-
         (profit, manage_debt_params) = _update_market_position_after_swap(
             position,
             amount_out_value,
             amount_remaining_value,
+            volume_share,
             interest_value,
         );
 
         _insert_account_position(account, position.clone());
     } else {
         let net_debt = position.debt_value + interest_value;
+        let amount_repaid = net_debt.min(amount_out_value + position.extra_margin);
         (profit, manage_debt_params) = (
-            amount_out_value - net_debt,
-            ManageDebtParams::init(position.debt_value, net_debt, net_debt),
+            amount_out_value + position.extra_margin - amount_repaid,
+            ManageDebtParams::init(position.debt_value, net_debt, amount_repaid),
         );
         _remove_account_position(&account);
     }
@@ -877,7 +2803,7 @@ fn _close_market_long_position(
             _get_next_best_offer_tick(false, resulting_tick, limit).unwrap_or(resulting_tick),
         );
     }
-    return (profit, crossed_ticks, manage_debt_params);
+    return Ok((profit, crossed_ticks, manage_debt_params));
 }
 
 /// Close Short Position
@@ -887,11 +2813,7 @@ fn _close_market_short_position(
     account: Subaccount,
     position: &mut PositionParameters,
     max_tick: Option<Tick>,
-) -> (Amount, Vec<Tick>, ManageDebtParams) {
-    let position_realised_value = _calc_position_realised_value(position.volume_share, false);
-
-    let realised_position_size = position_realised_value;
-
+) -> Result<(Amount, Vec<Tick>, ManageDebtParams), MarketError> {
     let lowest_sell_offer_tick = _get_lowest_sell_offer_tick();
 
     let MarketDetails { tick_spacing, .. } = _get_market_details();
@@ -903,22 +2825,45 @@ fn _close_market_short_position(
     let Some(starting_tick) =
         _get_next_best_offer_tick(true, lowest_sell_offer_tick, stopping_tick)
     else {
-        return (
-            0,
-            Vec::new(),
-            ManageDebtParams::init(position.debt_value, position.debt_value, 0),
-        );
+        return Err(MarketError::NoLiquidity);
+    };
+
+    let realised_position_size = _get_position_volume_value(position.volume_share, false)?;
+
+    // the swap is simulated first ,so every calculation on its outcome is made before the book is modified
+    let (amount_out, amount_remaining_value, _, _) =
+        _simulated_swap(true, starting_tick, stopping_tick, realised_position_size)?;
+
+    let amount_out_value = _equivalent(amount_out, starting_tick, false)?;
+
+    // the position's volume is removed from the funding rate tracker and any unfilled amount added back
+    let mut funding_rate_tracker = _get_funding_rate_tracker();
+    funding_rate_tracker.remove_volume(position.volume_share, false)?;
+    let volume_share = if amount_remaining_value > 0 {
+        funding_rate_tracker.add_volume(amount_remaining_value, false)?
+    } else {
+        0
     };
 
-    let (amount_out, amount_remaining_value, resulting_tick, crossed_ticks) =
-        _swap(realised_position_size, true, starting_tick, stopping_tick);
+    let (_, _, resulting_tick, crossed_ticks) = _swap(
+        account,
+        realised_position_size,
+        true,
+        starting_tick,
+        stopping_tick,
+    )?;
+
+    _set_funding_rate_tracker(funding_rate_tracker);
 
-    let amount_out_value = _equivalent(amount_out, starting_tick, false);
+    // the taker fee is taken from the swap proceeds before the debt is settled
+    let taker_fee = _charge_trading_fee(amount_out_value, false, amount_out_value);
+    let amount_out_value = amount_out_value - taker_fee;
 
     let interest_value = _calc_interest(
         position.debt_value,
         position.interest_rate,
         position.timestamp,
+        &IcClock,
     );
 
     let profit: u128;
@@ -929,15 +2874,17 @@ fn _close_market_short_position(
             position,
             amount_out_value,
             amount_remaining_value,
+            volume_share,
             interest_value,
         );
 
         _insert_account_position(account, position.clone());
     } else {
         let net_debt = position.debt_value + interest_value;
+        let amount_repaid = net_debt.min(amount_out_value + position.extra_margin);
         (profit, manage_debt_params) = (
-            amount_out_value - net_debt,
-            ManageDebtParams::init(position.debt_value, net_debt, net_debt),
+            amount_out_value + position.extra_margin - amount_repaid,
+            ManageDebtParams::init(position.debt_value, net_debt, amount_repaid),
         );
 
         _remove_account_position(&account);
@@ -950,7 +2897,239 @@ fn _close_market_short_position(
         );
     };
 
-    return (profit, crossed_ticks, manage_debt_params);
+    return Ok((profit, crossed_ticks, manage_debt_params));
+}
+
+/// Close Market Position Partially
+///
+/// Closes a share of a market position ,repaying a proportional slice of the debt and the interest on that slice
+/// and leaving the rest of the position intact
+///
+/// Params
+///  - Account :The account of the position
+///  - Position :A mutable reference to the position
+///  - Close Share :The share of the position's volume share to close ,this must be less than the position's volume share
+///  - Max Tick :The max tick,corresponds to max price
+///
+/// Returns
+///  - Profit :The amount to send to position owner
+///  - Crosssed Ticks :An array of ticks that have been crossed during swapping
+///  - Manage Debt Params :for repaying the debt slice
+///  - or NoLiquidity if there is no offer to close against
+///
+/// Note
+///  - If the share can not be closed fully ,the unfilled amount is added back to the position and only the filled
+///  share is removed from the position collateral and debt
+fn _close_market_position_partially(
+    account: Subaccount,
+    position: &mut PositionParameters,
+    close_share: Amount,
+    max_tick: Option<Tick>,
+) -> Result<(Amount, Vec<Tick>, ManageDebtParams), MarketError> {
+    let MarketDetails { tick_spacing, .. } = _get_market_details();
+
+    let (best_offer_tick, stopping_tick);
+    if position.long {
+        best_offer_tick = _get_highest_buy_offer_tick();
+        stopping_tick = max_tick
+            .map(|x| _compressed_tick(x, tick_spacing))
+            .unwrap_or(_def_max_tick(best_offer_tick, false));
+    } else {
+        best_offer_tick = _get_lowest_sell_offer_tick();
+        stopping_tick = max_tick
+            .map(|x| _compressed_tick(x, tick_spacing))
+            .unwrap_or(_def_max_tick(best_offer_tick, true));
+    }
+
+    let Some(starting_tick) =
+        _get_next_best_offer_tick(!position.long, best_offer_tick, stopping_tick)
+    else {
+        return Err(MarketError::NoLiquidity);
+    };
+
+    let close_value = _get_position_volume_value(close_share, position.long)?;
+
+    // the swap is simulated first ,so every calculation on its outcome is made before the book is modified
+    let (order_size, amount_out_value, amount_remaining_value);
+
+    if position.long {
+        order_size = _equivalent(close_value, position.entry_tick, true)?;
+
+        let (amount_out, amount_remaining, _, _) =
+            _simulated_swap(false, starting_tick, stopping_tick, order_size)?;
+
+        amount_out_value = amount_out;
+        amount_remaining_value = _equivalent(amount_remaining, position.entry_tick, false)?;
+    } else {
+        order_size = close_value;
+
+        let (amount_out, amount_remaining, _, _) =
+            _simulated_swap(true, starting_tick, stopping_tick, order_size)?;
+
+        amount_out_value = _equivalent(amount_out, starting_tick, false)?;
+        amount_remaining_value = amount_remaining;
+    }
+
+    let amount_remaining_value = amount_remaining_value.min(close_value);
+
+    // the share actually closed ,excluding the unfilled amount that is added back to the position
+    let filled_share = (close_share * (close_value - amount_remaining_value))
+        .checked_div(close_value)
+        .unwrap_or(0);
+
+    let remaining_share = position.volume_share - close_share;
+
+    // the share's volume is removed from the funding rate tracker and any unfilled amount added back
+    let mut funding_rate_tracker = _get_funding_rate_tracker();
+    funding_rate_tracker.remove_volume(close_share, position.long)?;
+
+    let unfilled_share = if amount_remaining_value > 0 {
+        funding_rate_tracker.add_volume(amount_remaining_value, position.long)?
+    } else {
+        0
+    };
+
+    let (_, _, resulting_tick, crossed_ticks) = _swap(
+        account,
+        order_size,
+        !position.long,
+        starting_tick,
+        stopping_tick,
+    )?;
+
+    _set_funding_rate_tracker(funding_rate_tracker);
+
+    if position.long {
+        if resulting_tick < starting_tick || crossed_ticks.contains(&starting_tick) {
+            let limit = _def_max_tick(resulting_tick, false);
+            _update_highest_buy_offer_tick(
+                _get_next_best_offer_tick(false, resulting_tick, limit).unwrap_or(resulting_tick),
+            );
+        }
+    } else if resulting_tick > starting_tick || crossed_ticks.contains(&starting_tick) {
+        let limit = _def_max_tick(resulting_tick, true);
+        _update_lowest_sell_offer_tick(
+            _get_next_best_offer_tick(true, resulting_tick, limit).unwrap_or(resulting_tick),
+        );
+    }
+
+    // the taker fee is taken from the swap proceeds before the debt slice is settled
+    let taker_fee = _charge_trading_fee(amount_out_value, false, amount_out_value);
+
+    let (profit, manage_debt_params) = _settle_position_close(
+        account,
+        position,
+        filled_share,
+        amount_out_value - taker_fee,
+        remaining_share + unfilled_share,
+    );
+
+    return Ok((profit, crossed_ticks, manage_debt_params));
+}
+
+/// Settle Position Close
+///
+/// Updates a position after a share of it has been closed and stores it
+///
+/// Params
+///  - Account :The account of the position
+///  - Position :A mutable reference to the position
+///  - Filled Share :The share of the position's volume share that was closed
+///  - Amount Out Value :The value received for closing the filled share
+///  - Resulting Share :The volume share of the position after closing
+///
+/// Returns
+///  - Profit :The amount to send to position owner
+///  - Manage Debt Params :for repaying the debt slice
+///
+/// Note
+///  - If the resulting share is zero ,the position is removed
+fn _settle_position_close(
+    account: Subaccount,
+    position: &mut PositionParameters,
+    filled_share: Amount,
+    amount_out_value: Amount,
+    resulting_share: Amount,
+) -> (Amount, ManageDebtParams) {
+    let (interest_rate, timestamp) = (position.interest_rate, position.timestamp);
+
+    let (profit, manage_debt_params) = _repay_debt_slice(
+        position,
+        filled_share,
+        amount_out_value,
+        |debt| _calc_interest(debt, interest_rate, timestamp, &IcClock),
+        IcClock.now(),
+    );
+
+    position.volume_share = resulting_share;
+
+    if resulting_share == 0 {
+        _remove_account_position(&account);
+    } else {
+        _insert_account_position(account, *position);
+    }
+
+    return (profit, manage_debt_params);
+}
+
+/// Repay Debt Slice
+///
+/// Removes the slice of a position's collateral and debt corresponding to a closed share and repays the debt slice
+/// with its interest out of the amount received for closing that share
+///
+/// Params
+///  - Position :A mutable reference to the position
+///  - Filled Share :The share of the position's volume share that was closed
+///  - Amount Out Value :The value received for closing the filled share
+///  - Accrued Interest :Returns the interest accrued on an amount of debt since the position's interest period started
+///  - Current Time :The time of the close ,the interest period restarts from here if the debt slice is not repaid
+///
+/// Returns
+///  - Profit :The amount to send to position owner
+///  - Manage Debt Params :for repaying the debt slice
+///
+/// Note
+///  - If the amount received can not repay the debt slice ,the interest on the whole debt is accrued and the shortfall
+///    remains as debt on the rest of the position
+fn _repay_debt_slice(
+    position: &mut PositionParameters,
+    filled_share: Amount,
+    amount_out_value: Amount,
+    accrued_interest: impl Fn(Amount) -> Amount,
+    current_time: Time,
+) -> (Amount, ManageDebtParams) {
+    let debt_slice = (position.debt_value * filled_share) / position.volume_share;
+    let collateral_slice = (position.collateral_value * filled_share) / position.volume_share;
+
+    let net_debt_slice = debt_slice + accrued_interest(debt_slice);
+
+    let init_debt_value = position.debt_value;
+
+    let (profit, manage_debt_params);
+
+    if amount_out_value < net_debt_slice {
+        // the interest on the rest of the debt is accrued too and the interest period restarted ,so the shortfall
+        // folded into the debt does not accrue interest from before the close
+        let net_debt_value = init_debt_value + accrued_interest(init_debt_value);
+
+        position.debt_value = net_debt_value - amount_out_value;
+        position.timestamp = current_time;
+
+        profit = 0;
+
+        manage_debt_params =
+            ManageDebtParams::init(init_debt_value, net_debt_value, amount_out_value);
+    } else {
+        position.debt_value = init_debt_value - debt_slice;
+
+        profit = amount_out_value - net_debt_slice;
+
+        manage_debt_params = ManageDebtParams::init(debt_slice, net_debt_slice, net_debt_slice);
+    }
+
+    position.collateral_value -= collateral_slice;
+
+    return (profit, manage_debt_params);
 }
 
 /// Close Limit Position
@@ -965,16 +3144,18 @@ fn _close_market_short_position(
 fn _close_limit_long_position(
     account: Subaccount,
     position: &mut PositionParameters,
-) -> (Amount, ManageDebtParams) {
+) -> Result<(Amount, ManageDebtParams), MarketError> {
     let PositionOrderType::Limit(order) = position.order_type else {
-        return (0, ManageDebtParams::default());
+        return Ok((0, ManageDebtParams::default()));
     };
 
-    let (amount_received, amount_remaining_value) = _close_order(&order);
+    let (amount_received, amount_remaining_value) = _get_order_amounts(&order)?;
 
     let (removed_collateral, manage_debt_params);
 
     if amount_received == 0 {
+        _close_order(&order)?;
+
         (removed_collateral, manage_debt_params) = (
             position.collateral_value,
             ManageDebtParams::init(
@@ -987,12 +3168,46 @@ fn _close_limit_long_position(
         _remove_account_position(&account);
     } else {
         (removed_collateral, manage_debt_params) =
-            _convert_limit_position(position, amount_remaining_value);
+            _convert_limit_position(position, &order, amount_remaining_value)?;
 
         _insert_account_position(account, position.clone());
+        _schedule_position_triggers_check();
+    };
+
+    return Ok((removed_collateral, manage_debt_params));
+}
+
+/// Close Limit Position
+///
+/// Closes the order of a limit position ,sends back the unfilled collateral and repays the unfilled debt
+///
+/// Returns
+///  - Removed Collateral :The collateral sent back to the position owner
+///
+/// Note
+///  - If the order is partially filled ,the position is converted into a market position of the filled amount
+///  - Returns CalculationError if the order amounts can not be calculated ,the position is left unchanged
+fn _close_limit_position(
+    user: Principal,
+    account_index: u8,
+    position: &mut PositionParameters,
+    vault: Vault,
+) -> Result<Amount, MarketError> {
+    let account = user._to_subaccount(account_index);
+
+    let (removed_collateral, manage_debt_params) = if position.long {
+        _close_limit_long_position(account, position)?
+    } else {
+        _close_limit_short_position(account, position)?
     };
 
-    return (removed_collateral, manage_debt_params);
+    remove_tick_order(position.entry_tick, account);
+    _set_limit_position_expiry(account, None);
+
+    if removed_collateral != 0 || manage_debt_params.amount_repaid != 0 {
+        vault.manage_position_update(user, account_index, removed_collateral, manage_debt_params);
+    }
+    return Ok(removed_collateral);
 }
 
 /// Close Limit Short Function
@@ -1001,16 +3216,18 @@ fn _close_limit_long_position(
 fn _close_limit_short_position(
     account: Subaccount,
     position: &mut PositionParameters,
-) -> (Amount, ManageDebtParams) {
+) -> Result<(Amount, ManageDebtParams), MarketError> {
     let PositionOrderType::Limit(order) = position.order_type else {
         // unreachable code
-        return (0, ManageDebtParams::default());
+        return Ok((0, ManageDebtParams::default()));
     };
-    let (amount_received, amount_remaining) = _close_order(&order);
+    let (amount_received, amount_remaining) = _get_order_amounts(&order)?;
 
     let (removed_collateral, manage_debt_params);
 
     if amount_received == 0 {
+        _close_order(&order)?;
+
         (removed_collateral, manage_debt_params) = (
             position.collateral_value,
             ManageDebtParams::init(
@@ -1023,14 +3240,15 @@ fn _close_limit_short_position(
     } else {
         // let entry_price = _tick_to_price(position.entry_tick);
 
-        let amount_remaining_value = _equivalent(amount_remaining, position.entry_tick, false);
+        let amount_remaining_value = _equivalent(amount_remaining, position.entry_tick, false)?;
         (removed_collateral, manage_debt_params) =
-            _convert_limit_position(position, amount_remaining_value);
+            _convert_limit_position(position, &order, amount_remaining_value)?;
 
         _insert_account_position(account, position.clone());
+        _schedule_position_triggers_check();
     };
 
-    return (removed_collateral, manage_debt_params);
+    return Ok((removed_collateral, manage_debt_params));
 }
 
 /// Update Market Position After Swap Function
@@ -1046,6 +3264,7 @@ fn _close_limit_short_position(
 ///  - Resulting Tick : The resulting tick after swapping to closing the position
 ///  - Amount Out Value :The value of the amount gotten from swapping
 ///  - Amount Remaining Value :The value of the amount remaining after swapping
+///  - Volume Share :The volume share of the amount remaining
 ///  - Interest Value : The value of the interest accrued on current position debt
 ///
 /// Returns
@@ -1055,6 +3274,7 @@ fn _update_market_position_after_swap(
     position: &mut PositionParameters,
     amount_out_value: Amount,
     amount_remaining_value: Amount,
+    volume_share: Amount,
     interest_value: Amount,
 ) -> (Amount, ManageDebtParams) {
     let init_debt_value = position.debt_value;
@@ -1081,9 +3301,7 @@ fn _update_market_position_after_swap(
             ManageDebtParams::init(init_debt_value, net_debt_value, net_debt_value);
     }
 
-    let new_volume_share = _calc_position_volume_share(amount_remaining_value, position.long);
-
-    position.volume_share = new_volume_share;
+    position.volume_share = volume_share;
 
     // if position last time updated is greater than one hour ago ,position time is updated to current timestamp
     if position.timestamp + ONE_HOUR > ic_cdk::api::time() {
@@ -1103,72 +3321,108 @@ fn _update_market_position_after_swap(
 /// Returns
 ///   - is Fully Filled :Returns true  the limit order has been fully filled or returns false otherwise
 ///   - is Partially Filled :true if the position partially filled
-fn _convert_account_limit_position_to_market(account: Subaccount, read: bool) -> PositionStatus {
-    let mut position = _get_account_position(&account).unwrap();
+///   - or CalculationError if the order amounts can not be calculated ,the position is left unchanged
+fn _convert_account_limit_position_to_market(
+    account: Subaccount,
+    read: bool,
+) -> Result<PositionStatus, MarketError> {
+    // the position may have been closed after its order was filled
+    let Some(mut position) = _get_account_position(&account) else {
+        return Ok(PositionStatus::UNFILLED);
+    };
 
     let mut position_status = PositionStatus::FILLED;
 
     if let PositionOrderType::Limit(order) = position.order_type {
-        let (amount_out, amount_remaining) = _close_order(&order);
+        let (amount_out, amount_remaining) = _get_order_amounts(&order)?;
         if amount_out == 0 {
             position_status = PositionStatus::UNFILLED;
         } else if amount_remaining > 0 {
             position_status = PositionStatus::PARTIAL
         };
         if read {
-            return position_status;
+            return Ok(position_status);
         }
         let amount_remaining_value = if position.long {
             amount_remaining
         } else {
             // let price = _tick_to_price(position.entry_tick);
-            _equivalent(amount_remaining, position.entry_tick, false)
+            _equivalent(amount_remaining, position.entry_tick, false)?
         };
-        _convert_limit_position(&mut position, amount_remaining_value);
+        let (removed_collateral, manage_debt_params) =
+            _convert_limit_position(&mut position, &order, amount_remaining_value)?;
         _insert_account_position(account, position);
+        _schedule_position_triggers_check();
+
+        // the unfilled collateral is sent back and the unfilled debt repaid
+        if removed_collateral != 0 || manage_debt_params.amount_repaid != 0 {
+            let vault = Vault::init(_get_market_details().vault_id);
+            vault.manage_position_update(
+                position.owner,
+                position.account_index,
+                removed_collateral,
+                manage_debt_params,
+            );
+        }
 
         // checking if order is completely filled
     }
-    return position_status;
+    return Ok(position_status);
 }
 
 /// Convert Limit Position function
 ///
-/// Converts a limit position into a market position after the reference limit order of that position has been filled fully or partially
+/// Closes the reference limit order of a limit position and converts the position into a market position after the order
+/// has been filled fully or partially
 /// any unfilled amount is refunded first as debt and if still remaining it is refunded back to the position owner and the position is updated to a market position
 ///
 /// Params
 ///  - Position : A mutable reference to the cuurent position
+///  - Order :The reference limit order of the position
 ///  - Amount Remaining Value : The value of the amount of  unfilled liquidity of the particular order
 ///
 /// Returns
 ///  - Removed Collateral : The amount of collateral removed from that position
 ///  - Update Asset Details Params :The update asset details params for updating asset detailsin params   
+///  - or CalculationError if the volume share can not be calculated ,the order and the position are left unchanged
 fn _convert_limit_position(
     position: &mut PositionParameters,
+    order: &LimitOrder,
     amount_remaining_value: Amount,
-) -> (Amount, ManageDebtParams) {
+) -> Result<(Amount, ManageDebtParams), MarketError> {
     let initial_collateral_value: u128 = position.collateral_value;
 
     let initial_debt_value = position.debt_value;
 
-    let removed_collateral;
+    let (removed_collateral, collateral_value, debt_value);
     if amount_remaining_value > initial_debt_value {
         removed_collateral = amount_remaining_value - initial_debt_value;
 
-        position.debt_value = 0;
-        position.collateral_value -= removed_collateral;
+        debt_value = 0;
+        collateral_value = initial_collateral_value - removed_collateral;
     } else {
         removed_collateral = 0;
 
-        position.debt_value -= amount_remaining_value;
+        debt_value = initial_debt_value - amount_remaining_value;
+        collateral_value = initial_collateral_value;
     }
 
     let remaining_order_value =
         initial_collateral_value + initial_debt_value - amount_remaining_value;
 
-    let volume_share = _calc_position_volume_share(remaining_order_value, position.long);
+    let maker_fee = _calc_trading_fee(remaining_order_value, true, collateral_value);
+
+    let mut funding_rate_tracker = _get_funding_rate_tracker();
+    let volume_share =
+        funding_rate_tracker.add_volume(remaining_order_value - maker_fee, position.long)?;
 
+    _close_order(order)?;
+
+    _set_funding_rate_tracker(funding_rate_tracker);
+    _accrue_trading_fee(maker_fee);
+
+    position.collateral_value = collateral_value - maker_fee;
+    position.debt_value = debt_value;
     position.volume_share = volume_share;
     position.order_type = PositionOrderType::Market;
     position.timestamp = ic_cdk::api::time();
@@ -1176,10 +3430,10 @@ fn _convert_limit_position(
     let manage_debt_params = ManageDebtParams::init(
         initial_debt_value,
         initial_debt_value,
-        initial_debt_value - position.debt_value,
+        initial_debt_value - debt_value,
     );
 
-    return (removed_collateral, manage_debt_params);
+    return Ok((removed_collateral, manage_debt_params));
 }
 
 /// Opens Order Functions
@@ -1196,11 +3450,28 @@ fn _open_order(order: &mut LimitOrder) {
                 order,
                 integrals_bitmaps,
                 ticks_details,
+                clock: &IcClock,
             };
             open_order_params.open_order();
         })
     });
 }
+/// Reduce Order Function
+///
+/// reduces the size of an unfilled order at its reference tick without changing its queue position
+///
+/// Returns
+///  - Reduced :false if the order has been partially or fully filled
+fn _reduce_order(order: &mut LimitOrder, new_order_size: Amount) -> bool {
+    TICKS_DETAILS.with_borrow_mut(|ticks_details| {
+        let mut amend_order_params = AmendOrderParams {
+            order,
+            ticks_details,
+        };
+        amend_order_params.reduce_order(new_order_size)
+    })
+}
+
 /// Close Order Function
 ///
 /// closes an order at a particular tick
@@ -1214,8 +3485,9 @@ fn _open_order(order: &mut LimitOrder) {
 /// Returns
 ///  - Amont Out :: This corresponds to the asset to be bought i.e perp(base) asset for a buy order or quote asset for a sell order
 ///  - Amount Remaining :: This amount remaining corrseponds to the amount of asset at that tick that is still unfilled
-fn _close_order(order: &LimitOrder) -> (Amount, Amount) {
-    TICKS_DETAILS.with_borrow_mut(|ticks_details| {
+///  - or CalculationError if the amounts can not be calculated ,the book is left untouched
+fn _close_order(order: &LimitOrder) -> Result<(Amount, Amount), MarketError> {
+    let amounts = TICKS_DETAILS.with_borrow_mut(|ticks_details| {
         INTEGRAL_BITMAPS.with_borrow_mut(|integrals_bitmaps| {
             let mut close_order_params = CloseOrderParams {
                 order,
@@ -1224,12 +3496,26 @@ fn _close_order(order: &LimitOrder) -> (Amount, Amount) {
             };
             close_order_params.close_order()
         })
-    })
+    })?;
+
+    return Ok(amounts);
+}
+
+/// Get Order Amounts
+///
+/// Returns the amounts closing an order would return without closing it ,so that any calculation on them
+/// can be made before the order is closed
+fn _get_order_amounts(order: &LimitOrder) -> Result<(Amount, Amount), MarketError> {
+    let amounts =
+        TICKS_DETAILS.with_borrow(|ticks_details| _order_amounts(order, ticks_details))?;
+
+    return Ok(amounts);
 }
 
 /// Swap Function
 ///
 /// Params
+///  - Taker :: The account executing the swap ,utilised for recording the trade
 ///  - Order Size :: Tha amount of asset in order
 ///  - Buy :: the order direction ,true for buy and false otherwise
 ///  - Init Tick :: The current state tick
@@ -1240,13 +3526,19 @@ fn _close_order(order: &LimitOrder) -> (Amount, Amount) {
 ///  - Amount Remaining :: The amount remaining from swapping
 ///  - resulting Tick :The last tick at which swap occured
 ///  - Crossed Ticks :: An vector of all ticks crossed during swap
+///  - or CalculationError if the swap could not be calculated
+///
+/// Note
+///  - If any amount was swapped ,the fill is recorded in the trade history
+///  - If a calculation fails the book is left untouched
 fn _swap(
+    taker: Subaccount,
     order_size: Amount,
     buy: bool,
     init_tick: Tick,
     stopping_tick: Tick,
-) -> (Amount, Amount, Tick, Vec<Tick>) {
-    TICKS_DETAILS.with_borrow_mut(|ticks_details| {
+) -> Result<(Amount, Amount, Tick, Vec<Tick>), MarketError> {
+    let swap_result = TICKS_DETAILS.with_borrow_mut(|ticks_details| {
         INTEGRAL_BITMAPS.with_borrow_mut(|integrals_bitmaps| {
             let mut swap_params = SwapParams {
                 buy,
@@ -1258,7 +3550,22 @@ fn _swap(
             };
             swap_params._swap()
         })
-    })
+    })?;
+
+    let trades: Vec<Trade> = swap_result
+        .fills
+        .iter()
+        .map(|fill| _record_trade(taker, buy, fill.tick, fill.amount_in, fill.amount_out))
+        .collect();
+
+    _update_candles(&trades);
+
+    return Ok((
+        swap_result.amount_out,
+        swap_result.amount_remaining,
+        swap_result.resulting_tick,
+        swap_result.crossed_ticks,
+    ));
 }
 
 /// Calculate Position PNL
@@ -1275,11 +3582,12 @@ fn _calculate_position_unrealised_pnl_and_net_debt_value(
         position.debt_value,
         position.interest_rate,
         position.timestamp,
+        &IcClock,
     );
 
     let net_debt_value = position.debt_value + interest_on_debt_value;
 
-    let current_tick = _get_lowest_sell_offer_tick() as i64;
+    let current_tick = _get_mark_tick() as i64;
     // let current_tick = _tick_to_price(current_tick) as i64;
     //let entry_price = _tick_to_price(position.entry_tick) as i64;
     let entry_tick = position.entry_tick as i64;
@@ -1306,7 +3614,9 @@ fn _calculate_position_unrealised_pnl_and_net_debt_value(
 ///  - Collateral_Remaining : Returns the current value of collateral within the position
 ///  - Net Debt Value :returns the net debt value
 ///
-/// Note :This collateral value can be less than zero in such case, a bad debt has occured
+/// Note
+///  - This collateral value can be less than zero in such case, a bad debt has occured
+///  - A position whose collateral value is zero or less is always liquidatable
 fn _liquidation_status(position: PositionParameters, max_leveragex10: u8) -> (bool, i128, Amount) {
     if let PositionOrderType::Market = position.order_type {
         let initial_position_value = position.collateral_value + position.debt_value;
@@ -1321,13 +3631,17 @@ fn _liquidation_status(position: PositionParameters, max_leveragex10: u8) -> (bo
             (initial_position_value + position_profit_or_loss) as i128 - (net_debt_value) as i128
         } else {
             (initial_position_value as i128) - (position_profit_or_loss + net_debt_value) as i128
-        };
+        } + position.extra_margin as i128;
+
+        // a position with no collateral left is liquidated regardless of its leverage
+        if current_collateral_value <= 0 {
+            return (true, current_collateral_value, net_debt_value);
+        }
 
         let current_leverage_x10 =
             ((net_debt_value as i128 + current_collateral_value) * 10) / current_collateral_value;
 
-        let to_liquidate =
-            current_collateral_value < 0 || current_leverage_x10.abs() as u8 >= max_leveragex10;
+        let to_liquidate = current_leverage_x10 >= max_leveragex10 as i128;
 
         return (to_liquidate, current_collateral_value, net_debt_value);
     }
@@ -1365,19 +3679,53 @@ fn _get_next_best_offer_tick(qforb: bool, starting_tick: Tick, max_tick: Tick) -
 }
 
 fn _update_highest_buy_offer_tick(next_tick: Tick) {
-    HIGHEST_BUY_OFFER.with_borrow_mut(|tick| *tick = next_tick)
+    HIGHEST_BUY_OFFER.with_borrow_mut(|tick| tick.set(next_tick).unwrap());
 }
 
 fn _update_lowest_sell_offer_tick(next_tick: Tick) {
-    LOWEST_SELL_OFFER.with_borrow_mut(|tick| *tick = next_tick)
+    LOWEST_SELL_OFFER.with_borrow_mut(|tick| tick.set(next_tick).unwrap());
 }
 
 fn _get_highest_buy_offer_tick() -> Tick {
-    HIGHEST_BUY_OFFER.with_borrow(|tick| tick.clone())
+    HIGHEST_BUY_OFFER.with_borrow(|tick| *tick.get())
 }
 
 fn _get_lowest_sell_offer_tick() -> Tick {
-    LOWEST_SELL_OFFER.with_borrow(|tick| tick.clone())
+    LOWEST_SELL_OFFER.with_borrow(|tick| *tick.get())
+}
+
+/// Get Mark Tick
+///
+/// Gets the tick utilised for valuing positions ,this is the median of
+///  - the index price if it was fetched within the max index price age
+///  - the book mid price if both sides of the book have offers
+///  - the tick of the last trade
+///
+/// Note
+///  - Falls back to the lowest sell offer tick if none of the prices is available
+///  - Returns a compressed tick
+fn _get_mark_tick() -> Tick {
+    let MarketDetails { tick_spacing, .. } = _get_market_details();
+
+    let mut prices: Vec<Tick> = Vec::new();
+
+    // the index price is stored as an uncompressed tick
+    let (index_tick, index_timestamp) = INDEX_PRICE.with_borrow(|reference| *reference.get());
+    if index_tick != 0 && ic_cdk::api::time() - index_timestamp <= _MAX_INDEX_PRICE_AGE {
+        prices.push(_compressed_tick(index_tick, tick_spacing));
+    }
+
+    let highest_buy_offer_tick = _get_highest_buy_offer_tick();
+    let lowest_sell_offer_tick = _get_lowest_sell_offer_tick();
+    if highest_buy_offer_tick != 0 && lowest_sell_offer_tick != 0 {
+        prices.push((highest_buy_offer_tick + lowest_sell_offer_tick) / 2);
+    }
+
+    if let Some((_, last_trade)) = TRADES.with_borrow(|reference| reference.last_key_value()) {
+        prices.push(last_trade.tick / tick_spacing);
+    }
+
+    return _median(prices).unwrap_or(lowest_sell_offer_tick);
 }
 
 ///////////////////////////////////////////////////////////////////////////////////////////////
@@ -1401,7 +3749,10 @@ async fn settle_funding_rate() {
 
     match xrc._get_exchange_rate(request).await {
         Ok(rate_result) => {
-            let current_price_tick = _get_lowest_sell_offer_tick() as u128;
+            _update_index_price(rate_result.rate, rate_result.metadata.decimals);
+
+            // the mark tick is compressed ,it is uncompressed to be converted to the exchange rate scale
+            let current_price_tick = (_get_mark_tick() * market_details.tick_spacing) as u128;
 
             let perp_price =
                 (current_price_tick * 10u128.pow(rate_result.metadata.decimals)) / _PRICE_FACTOR;
@@ -1416,15 +3767,88 @@ async fn settle_funding_rate() {
     }
 }
 
+/// Refresh Index Price
+///
+/// Fetches the spot price of the market pair from the XRC canister and stores it as the index price
+async fn refresh_index_price() {
+    let market_details = _get_market_details();
+
+    let xrc = XRC::init(market_details.xrc_id);
+
+    let request = GetExchangeRateRequest {
+        base_asset: market_details.base_asset,
+        quote_asset: market_details.quote_asset,
+        timestamp: None,
+    };
+
+    if let Ok(rate_result) = xrc._get_exchange_rate(request).await {
+        _update_index_price(rate_result.rate, rate_result.metadata.decimals);
+    }
+}
+
+/// Update Index Price
+///
+/// Converts an exchange rate to a tick and stores it as the index price
+///
+/// Params
+///  - Rate :The exchange rate ,scaled by 10^decimals
+///  - Decimals :The decimals of the exchange rate
+fn _update_index_price(rate: u64, decimals: u32) {
+    let index_tick = (rate as u128 * _PRICE_FACTOR) / 10u128.pow(decimals);
+
+    INDEX_PRICE.with_borrow_mut(|reference| {
+        reference
+            .set((index_tick as Tick, ic_cdk::api::time()))
+            .unwrap()
+    });
+}
+
+/// Settle Funding Rate
+///
+/// Applies the funding rate premium to the market directions volume and records the settlement
 fn _settle_funding_rate(perp_price: u128, spot_price: u128) {
-    let funding_rate = _calculate_funding_rate_premium(perp_price, spot_price);
-    FUNDING_RATE_TRACKER.with_borrow_mut(|reference| {
+    let funding_rate = _calculate_funding_rate(perp_price, spot_price, _get_state_details());
+    let settlement = FUNDING_RATE_TRACKER.with_borrow_mut(|reference| {
         let mut funding_rate_tracker = reference.get().clone();
+        let volume_before = (
+            funding_rate_tracker.net_volume_long,
+            funding_rate_tracker.net_volume_short,
+        );
 
-        funding_rate_tracker.settle_funding_rate(funding_rate.abs() as u64, funding_rate > 0);
+        funding_rate_tracker
+            .settle_funding_rate(funding_rate.abs() as u64, funding_rate > 0)
+            .ok()?;
+
+        let volume_after = (
+            funding_rate_tracker.net_volume_long,
+            funding_rate_tracker.net_volume_short,
+        );
 
         reference.set(funding_rate_tracker).unwrap();
-    })
+        return Some((volume_before, volume_after));
+    });
+
+    // settlement is skipped if it can not be calculated
+    let Some((volume_before, volume_after)) = settlement else {
+        return;
+    };
+
+    FUNDING_HISTORY.with_borrow_mut(|reference| {
+        let settlement_id = reference.len();
+        reference.insert(
+            settlement_id,
+            FundingSettlement {
+                timestamp: ic_cdk::api::time(),
+                perp_price,
+                spot_price,
+                funding_rate,
+                net_volume_long_before: volume_before.0,
+                net_volume_short_before: volume_before.1,
+                net_volume_long_after: volume_after.0,
+                net_volume_short_after: volume_after.1,
+            },
+        );
+    });
 }
 
 fn _calculate_funding_rate_premium(perp_price: u128, spot_price: u128) -> i64 {
@@ -1432,34 +3856,62 @@ fn _calculate_funding_rate_premium(perp_price: u128, spot_price: u128) -> i64 {
         / spot_price as i128;
     return funding_rate as i64;
 }
-///Calculate Position Realised value
+
+/// Calculate Funding Rate
 ///
-///Calculates the Realised value for a position's volume share in a particular market direction,Long or Short   
+/// Calculates the funding rate applied in a single settlement from the premium
 ///
-/// Note:This function also adjust's the volume share
-fn _calc_position_realised_value(volume_share: Amount, long: bool) -> Amount {
-    FUNDING_RATE_TRACKER.with_borrow_mut(|tr| {
-        let mut funding_rate_tracker = tr.get().clone();
+/// Note
+///  - The premium is scaled by the funding damping factor ,the funding interest rate is added and the result is
+///  clamped to the max funding rate
+///  - The max funding rate and the funding damping factor default to _DEFAULT_MAX_FUNDING_RATE and
+///    _DEFAULT_FUNDING_DAMPING_FACTOR if not set
+fn _calculate_funding_rate(perp_price: u128, spot_price: u128, state_details: StateDetails) -> i64 {
+    let premium = _calculate_funding_rate_premium(perp_price, spot_price);
+
+    let funding_damping_factor = if state_details.funding_damping_factor == 0 {
+        _DEFAULT_FUNDING_DAMPING_FACTOR
+    } else {
+        state_details.funding_damping_factor
+    };
 
-        let value = funding_rate_tracker.remove_volume(volume_share, long);
+    let damped_premium =
+        ((premium as i128 * funding_damping_factor as i128) / (100 * _ONE_PERCENT) as i128) as i64;
 
-        tr.set(funding_rate_tracker).unwrap();
-        value
-    })
-}
-/// Calculate Position Volume Share
-///
-/// Calculates the volume share for a particular poistion volume in a market direction ,Long or Short
-fn _calc_position_volume_share(position_value: Amount, long: bool) -> Amount {
-    FUNDING_RATE_TRACKER.with_borrow_mut(|tr| {
-        let mut funding_rate_tracker = tr.get().clone();
+    let max_funding_rate = if state_details.max_funding_rate == 0 {
+        _DEFAULT_MAX_FUNDING_RATE
+    } else {
+        state_details.max_funding_rate
+    };
 
-        let value = funding_rate_tracker.add_volume(position_value, long);
+    let max_funding_rate = max_funding_rate.min(i64::MAX as u64) as i64;
 
-        tr.set(funding_rate_tracker).unwrap();
-        value
+    return (damped_premium + state_details.funding_interest_rate)
+        .clamp(-max_funding_rate, max_funding_rate);
+}
+/// Get Position Volume Value
+///
+/// Returns the current value of a volume share in a market direction without removing it
+fn _get_position_volume_value(volume_share: Amount, long: bool) -> Result<Amount, MarketError> {
+    FUNDING_RATE_TRACKER.with_borrow(|tr| {
+        let funding_rate_tracker = tr.get();
+        if long {
+            _calc_shares_value(
+                volume_share,
+                funding_rate_tracker.total_long_shares,
+                funding_rate_tracker.net_volume_long,
+            )
+        } else {
+            _calc_shares_value(
+                volume_share,
+                funding_rate_tracker.total_short_shares,
+                funding_rate_tracker.net_volume_short,
+            )
+        }
+        .map_err(MarketError::from)
     })
 }
+
 ////////////////////////////////////////////////////////////////////////////////////////////////
 ///////////////////////////////////////////////////////////////////////////////////////////////
 ////////////////////////////////////////////////////////////////////////////////////////////////
@@ -1487,13 +3939,22 @@ fn _calc_position_volume_share(position_value: Amount, long: bool) -> Amount {
 /// fn _execute_each_limit_order()
 ///  /// The function gets the array mapped to a pariicular tick  and add each element
 /// ```
+///
+/// Limit Positions and Account Limit Orders
+///  - a limit position (openLimitPosition) is the account's position itself waiting at its entry tick ,it exists before it is filled
+///    so that its triggers and expiry can be set and it is closed or amended like any position ,an account holds at most one
+///    and it is tracked by account in LIMIT ORDERS RECORD
+///  - an account limit order (placeLimitOrder ,placeReduceOnlyOrder ,placeTakeProfitOrder) is a working order identified by its order id
+///    that adds to or reduces the account's market position once filled ,an account can hold several and it is tracked by
+///    account and order id in ACCOUNTS LIMIT ORDERS and TICKS LIMIT ORDERS
+///  - the two are kept apart as a limit position has no market position to fill into ,account limit orders can not be placed while
+///    the account has a limit position and a limit position can not be opened while the account has working limit orders
+///  - both share the execution path ,crossed ticks move them into their executable maps and a single timer executes them one per call
 //
 
 pub fn store_tick_order(tick: Tick, account: Subaccount) {
     LIMIT_ORDERS_RECORD.with_borrow_mut(|reference| {
-        let accounts = reference.entry(tick).or_insert(Vec::new());
-
-        accounts.push(account);
+        reference.insert((tick, account), ());
     })
 }
 
@@ -1508,12 +3969,305 @@ pub fn store_tick_order(tick: Tick, account: Subaccount) {
 /// - Account : The account closing the position
 pub fn remove_tick_order(tick: Tick, account: Subaccount) {
     LIMIT_ORDERS_RECORD.with_borrow_mut(|reference| {
-        let accounts = reference.get_mut(&tick).unwrap();
+        reference.remove(&(tick, account));
+    })
+}
+
+/// Place Limit Order Checks
+///
+/// Checks that an account can place an additional limit order in a direction
+fn _place_limit_order_checks(account: &Subaccount, long: bool) -> Result<(), MarketError> {
+    let has_pending_error =
+        ACCOUNTS_ERROR_LOGS.with_borrow(|reference| reference.contains_key(account));
 
-        let index = accounts.iter().position(|x| x == &account).unwrap();
+    if has_pending_error {
+        return Err(MarketError::PendingErrorLog);
+    }
 
-        accounts.remove(index);
-    })
+    if let Some(position) = _get_account_position(account) {
+        if let PositionOrderType::Limit(_) = position.order_type {
+            return Err(MarketError::LimitOrdersConflict);
+        }
+        if position.long != long {
+            return Err(MarketError::DirectionConflict);
+        }
+    }
+
+    if _get_account_limit_orders_direction(account).is_some_and(|orders_long| orders_long != long) {
+        return Err(MarketError::DirectionConflict);
+    }
+
+    if _get_account_limit_orders_count(account) >= _MAX_ACCOUNT_LIMIT_ORDERS {
+        return Err(MarketError::MaxOrdersReached);
+    }
+
+    return Ok(());
+}
+
+/// Execute Account Limit Order
+///
+/// Closes an account's limit order ,adds the filled amount to the account's position and sends back the unfilled amount
+///
+/// Returns
+///  - Removed Collateral :The amount of collateral sent back to the account owner
+///  - or LimitOrdersConflict if the account has an open limit position which the filled amount can not be added to
+///  - or CalculationError if the fill can not be calculated ,the order and the position are left unchanged
+fn _execute_account_limit_order(
+    account: Subaccount,
+    account_order: AccountLimitOrder,
+) -> Result<Amount, MarketError> {
+    if account_order.reduce_only {
+        return _execute_reduce_only_order(account, account_order);
+    }
+
+    if let Some(position) = _get_account_position(&account) {
+        if let PositionOrderType::Limit(_) = position.order_type {
+            return Err(MarketError::LimitOrdersConflict);
+        }
+    }
+
+    let (amount_out, amount_remaining) = _get_order_amounts(&account_order.order)?;
+
+    let vault = Vault::init(_get_market_details().vault_id);
+
+    let AccountLimitOrder {
+        collateral_value,
+        debt_value,
+        ..
+    } = account_order;
+
+    if amount_out == 0 {
+        _close_order(&account_order.order)?;
+        _remove_account_limit_order(&account, &account_order);
+
+        vault.manage_position_update(
+            account_order.owner,
+            account_order.account_index,
+            collateral_value,
+            ManageDebtParams::init(debt_value, debt_value, debt_value),
+        );
+        return Ok(collateral_value);
+    }
+
+    let amount_remaining_value = if account_order.long {
+        amount_remaining
+    } else {
+        _equivalent(amount_remaining, account_order.order.ref_tick, false)?
+    };
+
+    // unfilled amount is refunded first as debt and if still remaining it is refunded back to the order owner
+    let (removed_collateral, filled_collateral_value, filled_debt_value) =
+        if amount_remaining_value > debt_value {
+            let removed_collateral = (amount_remaining_value - debt_value).min(collateral_value);
+            (removed_collateral, collateral_value - removed_collateral, 0)
+        } else {
+            (0, collateral_value, debt_value - amount_remaining_value)
+        };
+
+    let maker_fee = _calc_trading_fee(
+        filled_collateral_value + filled_debt_value,
+        true,
+        filled_collateral_value,
+    );
+
+    let mut funding_rate_tracker = _get_funding_rate_tracker();
+
+    let (position, accrued_debt_params) = _add_fill_to_account_position(
+        account,
+        account_order,
+        filled_collateral_value - maker_fee,
+        filled_debt_value,
+        &mut funding_rate_tracker,
+    )?;
+
+    _close_order(&account_order.order)?;
+    _remove_account_limit_order(&account, &account_order);
+
+    _set_funding_rate_tracker(funding_rate_tracker);
+    _accrue_trading_fee(maker_fee);
+    _insert_account_position(account, position);
+
+    if removed_collateral != 0
+        || filled_debt_value != debt_value
+        || accrued_debt_params.net_debt != accrued_debt_params.initial_debt
+    {
+        vault.manage_position_update(
+            account_order.owner,
+            account_order.account_index,
+            removed_collateral,
+            ManageDebtParams::init(
+                debt_value + accrued_debt_params.initial_debt,
+                debt_value + accrued_debt_params.net_debt,
+                debt_value - filled_debt_value,
+            ),
+        );
+    }
+
+    return Ok(removed_collateral);
+}
+
+/// Execute Reduce Only Order
+///
+/// Closes a reduce only order and closes the filled share of the account's market position
+///
+/// Returns
+///  - Profit :The amount sent to the position owner
+///  - or CalculationError if the fill can not be calculated ,the order and the position are left unchanged
+///
+/// Note
+///  - The share closed is capped by the remaining volume share of the position ,the value of any fill beyond it
+///    is paid out to the owner without closing the position
+fn _execute_reduce_only_order(
+    account: Subaccount,
+    account_order: AccountLimitOrder,
+) -> Result<Amount, MarketError> {
+    let (amount_out, amount_remaining) = _get_order_amounts(&account_order.order)?;
+
+    let order_size = account_order.order.order_size;
+
+    if amount_out == 0 || order_size == 0 {
+        _close_order(&account_order.order)?;
+        _remove_account_limit_order(&account, &account_order);
+        return Ok(0);
+    }
+
+    // the position may have been closed since the order was placed
+    let position = _get_account_position(&account)
+        .filter(|position| matches!(position.order_type, PositionOrderType::Market));
+
+    let fill_share = (account_order.close_share * (order_size - amount_remaining)) / order_size;
+
+    // a sell order closes a long position and is paid in the quote asset
+    let fill_value = match position {
+        Some(position) if position.long => amount_out,
+        Some(position) => _equivalent(amount_out, position.entry_tick, false)?,
+        None if !account_order.long => amount_out,
+        None => _equivalent(amount_out, account_order.order.ref_tick, false)?,
+    };
+
+    let filled_share = position.map_or(0, |position| fill_share.min(position.volume_share));
+
+    let closed_value = (fill_value * filled_share)
+        .checked_div(fill_share)
+        .unwrap_or(0);
+
+    let excess_value = fill_value - closed_value;
+
+    let mut funding_rate_tracker = _get_funding_rate_tracker();
+
+    if let Some(position) = position.filter(|_| filled_share != 0) {
+        funding_rate_tracker.remove_volume(filled_share, position.long)?;
+    }
+
+    _close_order(&account_order.order)?;
+    _remove_account_limit_order(&account, &account_order);
+
+    _set_funding_rate_tracker(funding_rate_tracker);
+
+    let (profit, manage_debt_params) = match position {
+        Some(mut position) if filled_share != 0 => {
+            let resulting_share = position.volume_share - filled_share;
+
+            _settle_position_close(
+                account,
+                &mut position,
+                filled_share,
+                closed_value,
+                resulting_share,
+            )
+        }
+        _ => (0, ManageDebtParams::default()),
+    };
+
+    let maker_fee = _charge_trading_fee(fill_value, true, profit + excess_value);
+
+    let payout = profit + excess_value - maker_fee;
+
+    let vault = Vault::init(_get_market_details().vault_id);
+
+    if payout != 0 || manage_debt_params.amount_repaid != 0 {
+        vault.manage_position_update(
+            account_order.owner,
+            account_order.account_index,
+            payout,
+            manage_debt_params,
+        );
+    }
+
+    return Ok(payout);
+}
+
+/// Add Fill To Account Position
+///
+/// Adds the filled amount of a limit order to the account's market position or opens a new market position if account has none
+///
+/// Params
+///  - Funding Rate Tracker :The funding rate tracker the volume of the fill is added to
+///
+/// Note
+///  - The entry tick of the resulting position is the average price of the position and the fill
+///  - The accrued interest on the position's debt is added to the debt and the interest rate becomes the debt weighted average
+///  - The resulting position is not stored ,so it can be stored once the order is closed
+///
+/// Returns
+///  - Position :The resulting position
+///  - Manage Debt Params :for telling the vault about the interest accrued on the position's debt
+///  - or CalculationError if the resulting position can not be calculated
+fn _add_fill_to_account_position(
+    account: Subaccount,
+    account_order: AccountLimitOrder,
+    filled_collateral_value: Amount,
+    filled_debt_value: Amount,
+    funding_rate_tracker: &mut FundingRateTracker,
+) -> Result<(PositionParameters, ManageDebtParams), MarketError> {
+    let filled_value = filled_collateral_value + filled_debt_value;
+
+    let ref_tick = account_order.order.ref_tick;
+
+    let Some(mut position) = _get_account_position(&account) else {
+        let position = PositionParameters {
+            owner: account_order.owner,
+            long: account_order.long,
+            entry_tick: ref_tick,
+            collateral_value: filled_collateral_value,
+            debt_value: filled_debt_value,
+            interest_rate: account_order.interest_rate,
+            volume_share: funding_rate_tracker.add_volume(filled_value, account_order.long)?,
+            order_type: PositionOrderType::Market,
+            timestamp: ic_cdk::api::time(),
+            extra_margin: 0,
+            account_index: account_order.account_index,
+        };
+        return Ok((position, ManageDebtParams::default()));
+    };
+
+    let position_value = _get_position_volume_value(position.volume_share, position.long)?;
+
+    let total_size = _equivalent(position_value, position.entry_tick, true)?
+        + _equivalent(filled_value, ref_tick, true)?;
+
+    let volume_share = funding_rate_tracker.add_volume(filled_value, position.long)?;
+
+    if total_size != 0 {
+        position.entry_tick =
+            (((position_value + filled_value) * _PRICE_FACTOR) / total_size) as Tick;
+    }
+
+    let manage_debt_params = _accrue_position_interest(&mut position);
+
+    let new_debt_value = position.debt_value + filled_debt_value;
+
+    if new_debt_value != 0 {
+        position.interest_rate = ((u128::from(position.interest_rate) * position.debt_value
+            + u128::from(account_order.interest_rate) * filled_debt_value)
+            / new_debt_value) as u32;
+    }
+
+    position.debt_value = new_debt_value;
+    position.collateral_value += filled_collateral_value;
+    position.volume_share += volume_share;
+
+    Ok((position, manage_debt_params))
 }
 
 /// Schedule Execution For Ticks Orders
@@ -1533,17 +4287,50 @@ fn _schedule_execution_for_ticks_orders(crossed_ticks: Vec<Tick>) {
 pub fn _execute_accounts_serialization_for_each_tick(ticks: Vec<Tick>) {
     EXECUTABLE_LIMIT_ORDERS_ACCOUNTS.with_borrow_mut(|accounts| {
         LIMIT_ORDERS_RECORD.with_borrow_mut(|reference| {
-            for tick in ticks {
-                let mut tick_accounts = reference.get_mut(&tick).unwrap();
-                //  let acc: Vec<Subaccount> = accounts.   .try_into().unwrap();
-                accounts.append(&mut tick_accounts);
+            for tick in &ticks {
+                let tick_accounts: Vec<(Tick, Subaccount)> = reference
+                    .range((*tick, [0u8; 32])..=(*tick, [u8::MAX; 32]))
+                    .map(|(key, _)| key)
+                    .collect();
+
+                for (tick, account) in tick_accounts {
+                    reference.remove(&(tick, account));
+                    accounts.insert(account, ());
+                }
             }
         });
     });
 
-    let pending_timer = _get_pending_timer();
+    // accounts limit orders at crossed ticks are moved into the executable accounts limit orders
+    for tick in ticks {
+        let tick_orders: Vec<(Tick, Subaccount, OrderId)> =
+            TICKS_LIMIT_ORDERS.with_borrow(|reference| {
+                reference
+                    .range((tick, [0u8; 32], 0)..=(tick, [u8::MAX; 32], OrderId::MAX))
+                    .map(|(key, _)| key)
+                    .collect()
+            });
+
+        for (tick, account, order_id) in tick_orders {
+            TICKS_LIMIT_ORDERS
+                .with_borrow_mut(|reference| reference.remove(&(tick, account, order_id)));
+            EXECUTABLE_ACCOUNTS_LIMIT_ORDERS
+                .with_borrow_mut(|reference| reference.insert((account, order_id), ()));
+        }
+    }
+
+    _schedule_limit_orders_execution();
+}
+
+/// Schedule Limit Orders Execution
+///
+/// Starts the limit orders execution timer if any executable order is pending and the timer is not already running
+fn _schedule_limit_orders_execution() {
+    let has_executable_orders = EXECUTABLE_LIMIT_ORDERS_ACCOUNTS
+        .with_borrow(|reference| !reference.is_empty())
+        || EXECUTABLE_ACCOUNTS_LIMIT_ORDERS.with_borrow(|reference| !reference.is_empty());
 
-    if pending_timer == TimerId::default() {
+    if has_executable_orders && _get_pending_timer() == TimerId::default() {
         let timer_id =
             ic_cdk_timers::set_timer_interval(Duration::from_nanos(6 * ONE_SECOND), || {
                 _execute_each_limit_order();
@@ -1556,16 +4343,26 @@ pub fn _execute_accounts_serialization_for_each_tick(ticks: Vec<Tick>) {
 /// Execute Each Limit Order
 ///
 fn _execute_each_limit_order() {
-    EXECUTABLE_LIMIT_ORDERS_ACCOUNTS.with_borrow_mut(|reference| {
-        if let Some(account) = reference.pop() {
-            _convert_account_limit_position_to_market(account, false);
-        } else {
-            let timer_id = _get_pending_timer();
-
-            ic_cdk_timers::clear_timer(timer_id);
-            _set_pending_timer(TimerId::default());
+    // the account is popped before converting as the conversion accesses the executable accounts
+    let executable_account =
+        EXECUTABLE_LIMIT_ORDERS_ACCOUNTS.with_borrow_mut(|reference| reference.pop_first());
+
+    if let Some((account, _)) = executable_account {
+        // a position whose order amounts can not be calculated is left as a limit position
+        let _ = _convert_account_limit_position_to_market(account, false);
+    } else if let Some(((account, order_id), _)) =
+        EXECUTABLE_ACCOUNTS_LIMIT_ORDERS.with_borrow_mut(|reference| reference.pop_first())
+    {
+        // an order that can not be executed is left on the book
+        if let Some(account_order) = _get_account_limit_order(&account, order_id) {
+            let _ = _execute_account_limit_order(account, account_order);
         }
-    })
+    } else {
+        let timer_id = _get_pending_timer();
+
+        ic_cdk_timers::clear_timer(timer_id);
+        _set_pending_timer(TimerId::default());
+    }
 }
 //////////////////////////////////////////////////////////////////////////////////////////////
 /////////////////////////////////////////////////////////////////////////////////////////////
@@ -1586,16 +4383,110 @@ fn admin_guard() -> Result<(), String> {
     })
 }
 
-#[ic_cdk::update(guard = "admin_guard", name = "updateStateDetails")]
-async fn update_state_details(new_state_details: StateDetails) {
-    _set_state_details(new_state_details);
+/// Update State Details
+///
+/// Note
+///  - The StateDetails record takes every field (see market.did) ,callers built against the earlier three field
+///    record (not_paused ,max_leveragex10 ,min_collateral) must be updated
+///  - If the funding interval is changed while the funding timer is running ,the timer is restarted with the new interval
+#[ic_cdk::update(guard = "admin_guard", name = "updateStateDetails")]
+async fn update_state_details(new_state_details: StateDetails) {
+    let funding_interval_changed =
+        _get_state_details().funding_interval != new_state_details.funding_interval;
+
+    _set_state_details(new_state_details);
+
+    if funding_interval_changed && _get_funding_timer() != TimerId::default() {
+        _schedule_funding_rate_settlement();
+    }
+}
+
+/// Get Accrued Trading Fees
+///
+/// Returns the trading fees accrued since they were last collected
+#[ic_cdk::query(name = "getAccruedTradingFees")]
+fn get_accrued_trading_fees() -> Amount {
+    ACCRUED_FEES.with_borrow(|reference| *reference.get())
+}
+
+/// Collect Trading Fees
+///
+/// Credits the accrued trading fees to the recipient's balance in the vault
+///
+/// Returns
+///  - Amount :The amount of fees collected
+///
+/// Note
+///  - If the vault does not credit the fees ,they are added back to the accrued fees and zero is returned so the
+///    collection can be retried
+#[ic_cdk::update(guard = "admin_guard", name = "collectTradingFees")]
+async fn collect_trading_fees(recipient: Principal) -> Amount {
+    let accrued_fees = ACCRUED_FEES.with_borrow_mut(|reference| {
+        let accrued_fees = *reference.get();
+        reference.set(0).unwrap();
+        return accrued_fees;
+    });
+
+    if accrued_fees == 0 {
+        return 0;
+    }
+
+    let vault = Vault::init(_get_market_details().vault_id);
+
+    if vault.credit_trading_fees(recipient, accrued_fees).await {
+        return accrued_fees;
+    }
+
+    // fees accrued while awaiting the vault are kept
+    ACCRUED_FEES.with_borrow_mut(|reference| {
+        let fees = *reference.get();
+        reference.set(fees + accrued_fees).unwrap();
+    });
+
+    return 0;
 }
 
+/// Start Timer
+///
+/// Starts the funding rate settlement and index price timers ,restarting them if already running
 #[ic_cdk::update(guard = "admin_guard", name = "startTimer")]
 async fn start_timer() {
-    ic_cdk_timers::set_timer_interval(Duration::from_nanos(ONE_HOUR), || {
-        ic_cdk::futures::spawn(settle_funding_rate())
-    });
+    _schedule_funding_rate_settlement();
+    _schedule_index_price_refresh();
+}
+
+/// Schedule Index Price Refresh
+///
+/// (Re)starts the index price timer
+fn _schedule_index_price_refresh() {
+    ic_cdk_timers::clear_timer(_get_index_price_timer());
+    let timer_id = ic_cdk_timers::set_timer_interval(
+        Duration::from_nanos(_INDEX_PRICE_UPDATE_INTERVAL),
+        || ic_cdk::futures::spawn(refresh_index_price()),
+    );
+    INDEX_PRICE_TIMER.with_borrow_mut(|reference| *reference = timer_id);
+}
+
+/// Schedule Funding Rate Settlement
+///
+/// (Re)starts the funding timer with the funding interval in the state details ,defaulting to one hour if not set
+fn _schedule_funding_rate_settlement() {
+    let StateDetails {
+        funding_interval, ..
+    } = _get_state_details();
+
+    let funding_interval = if funding_interval == 0 {
+        ONE_HOUR
+    } else {
+        funding_interval
+    };
+
+    ic_cdk_timers::clear_timer(_get_funding_timer());
+    let timer_id =
+        ic_cdk_timers::set_timer_interval(Duration::from_nanos(funding_interval), || {
+            ic_cdk::futures::spawn(settle_funding_rate())
+        });
+    FUNDING_TIMER.with_borrow_mut(|reference| *reference = timer_id);
 }
 
 //////////////////////////////////////////////////////////////////////////////////////////////////
@@ -1618,11 +4509,14 @@ fn trusted_canister_guard() -> Result<(), String> {
 }
 
 #[ic_cdk::update(name = "retryAccountError")]
-async fn retry_account_error(_user_account: Subaccount) {
-    let account_error_log = _get_account_error_log(&_user_account);
+async fn retry_account_error(_user_account: Subaccount) -> Result<(), MarketError> {
+    let Some(account_error_log) = _get_account_error_log(&_user_account) else {
+        return Err(MarketError::NoPendingErrorLog);
+    };
 
     let details = _get_market_details();
     account_error_log.retry(details);
+    return Ok(());
 }
 
 #[ic_cdk::update(name = "successNotification", guard = "trusted_canister_guard")]
@@ -1653,12 +4547,16 @@ fn _get_state_details() -> StateDetails {
     STATE_DETAILS.with_borrow(|ref_state_detaills| *ref_state_detaills.get())
 }
 
+fn _get_funding_rate_tracker() -> FundingRateTracker {
+    FUNDING_RATE_TRACKER.with_borrow(|reference| *reference.get())
+}
+
 fn _get_account_position(account: &Subaccount) -> Option<PositionParameters> {
     ACCOUNTS_POSITION.with_borrow(|ref_position_details| ref_position_details.get(&account))
 }
 
-fn _get_account_error_log(account: &Subaccount) -> PositionUpdateErrorLog {
-    ACCOUNTS_ERROR_LOGS.with_borrow(|reference| reference.get(account).unwrap())
+fn _get_account_error_log(account: &Subaccount) -> Option<PositionUpdateErrorLog> {
+    ACCOUNTS_ERROR_LOGS.with_borrow(|reference| reference.get(account))
 }
 
 fn _get_tick_details(tick: Tick) -> Option<TickDetails> {
@@ -1669,12 +4567,141 @@ fn _get_pending_timer() -> TimerId {
     PENDING_TIMER.with_borrow(|reference| reference.clone())
 }
 
-fn _has_position_or_pending_error_log(_account: &Subaccount) -> bool {
-    let has_position = ACCOUNTS_POSITION.with_borrow(|reference| reference.contains_key(_account));
-    let has_pending_error =
-        ACCOUNTS_ERROR_LOGS.with_borrow(|reference| reference.contains_key(_account));
+fn _get_position_triggers(account: &Subaccount) -> Option<PositionTriggers> {
+    POSITIONS_TRIGGERS.with_borrow(|reference| reference.get(account))
+}
+
+fn _get_account_limit_order(account: &Subaccount, order_id: OrderId) -> Option<AccountLimitOrder> {
+    ACCOUNTS_LIMIT_ORDERS.with_borrow(|reference| reference.get(&(*account, order_id)))
+}
+
+fn _get_account_limit_orders_count(account: &Subaccount) -> usize {
+    ACCOUNTS_LIMIT_ORDERS.with_borrow(|reference| {
+        reference
+            .range((*account, 0)..=(*account, OrderId::MAX))
+            .count()
+    })
+}
+
+/// Get Account Limit Orders Direction
+///
+/// Returns the direction of an account's working limit orders ,true if long or none if account has no working order
+///
+/// Note
+///  - Reduce only orders are not considered as they only close the account's position
+fn _get_account_limit_orders_direction(account: &Subaccount) -> Option<bool> {
+    ACCOUNTS_LIMIT_ORDERS.with_borrow(|reference| {
+        reference
+            .range((*account, 0)..=(*account, OrderId::MAX))
+            .find(|(_, account_order)| !account_order.reduce_only)
+            .map(|(_, account_order)| account_order.long)
+    })
+}
+
+/// Get Account Reduce Only Share
+///
+/// Returns the total volume share committed to closing by an account's reduce only orders
+fn _get_account_reduce_only_share(account: &Subaccount) -> Amount {
+    ACCOUNTS_LIMIT_ORDERS.with_borrow(|reference| {
+        reference
+            .range((*account, 0)..=(*account, OrderId::MAX))
+            .filter(|(_, account_order)| account_order.reduce_only)
+            .map(|(_, account_order)| account_order.close_share)
+            .sum()
+    })
+}
+
+/// Close Amount To Share
+///
+/// Converts a close amount to a share of a position's volume share
+fn _close_amount_to_share(
+    position: &PositionParameters,
+    close_amount: CloseAmount,
+) -> Result<Amount, MarketError> {
+    let close_share = match close_amount {
+        CloseAmount::Fraction(fraction) => _percentage(fraction, position.volume_share),
+        CloseAmount::Value(value) => {
+            let position_value = _get_position_volume_value(position.volume_share, position.long)?;
+            if position_value == 0 {
+                return Err(MarketError::InvalidAmount);
+            }
+            (position.volume_share * value) / position_value
+        }
+    };
+
+    if close_share == 0 {
+        return Err(MarketError::InvalidAmount);
+    }
+
+    return Ok(close_share);
+}
+
+/// Charge Trading Fee
+///
+/// Calculates the maker or taker fee on a volume ,adds the insurance fund share to the insurance fund and accrues the rest
+///
+/// Params
+///  - Volume :The filled value in the quote asset
+///  - Maker :true if the fee is charged on a limit order fill and false if it is charged on a swap
+///  - Max Fee :The max amount that can be charged ,i.e the collateral or swap proceeds the fee is taken from
+///
+/// Returns
+///  - Fee :The fee charged
+fn _charge_trading_fee(volume: Amount, maker: bool, max_fee: Amount) -> Amount {
+    let fee = _calc_trading_fee(volume, maker, max_fee);
+
+    _accrue_trading_fee(fee);
+
+    return fee;
+}
+
+/// Calculate Trading Fee
+///
+/// Calculates the maker or taker fee on a volume without charging it (see Charge Trading Fee for params)
+fn _calc_trading_fee(volume: Amount, maker: bool, max_fee: Amount) -> Amount {
+    let StateDetails {
+        maker_fee_rate,
+        taker_fee_rate,
+        ..
+    } = _get_state_details();
+
+    let fee_rate = if maker {
+        maker_fee_rate
+    } else {
+        taker_fee_rate
+    };
+
+    _percentage(fee_rate, volume).min(max_fee)
+}
+
+/// Accrue Trading Fee
+///
+/// Adds the insurance fund share of a calculated fee to the insurance fund and accrues the rest
+fn _accrue_trading_fee(fee: Amount) {
+    let insurance_fund_fee = _percentage(_get_state_details().insurance_fund_share, fee).min(fee);
+    _add_to_insurance_fund(insurance_fund_fee);
+
+    ACCRUED_FEES.with_borrow_mut(|reference| {
+        let accrued_fees = *reference.get();
+        reference
+            .set(accrued_fees + fee - insurance_fund_fee)
+            .unwrap();
+    });
+}
 
-    return has_pending_error || has_position;
+fn _get_funding_timer() -> TimerId {
+    FUNDING_TIMER.with_borrow(|reference| *reference)
+}
+fn _get_triggers_timer() -> TimerId {
+    TRIGGERS_TIMER.with_borrow(|reference| *reference)
+}
+
+fn _get_index_price_timer() -> TimerId {
+    INDEX_PRICE_TIMER.with_borrow(|reference| *reference)
+}
+
+fn _get_expiry_timer() -> TimerId {
+    EXPIRY_TIMER.with_borrow(|reference| *reference)
 }
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////
@@ -1689,6 +4716,10 @@ fn _set_state_details(new_state: StateDetails) {
     STATE_DETAILS.with_borrow_mut(|ref_state_details| ref_state_details.set(new_state).unwrap());
 }
 
+fn _set_funding_rate_tracker(funding_rate_tracker: FundingRateTracker) {
+    FUNDING_RATE_TRACKER.with_borrow_mut(|reference| reference.set(funding_rate_tracker).unwrap());
+}
+
 fn _insert_account_position(account: Subaccount, position: PositionParameters) {
     ACCOUNTS_POSITION
         .with_borrow_mut(|ref_users_position| ref_users_position.insert(account, position));
@@ -1696,6 +4727,124 @@ fn _insert_account_position(account: Subaccount, position: PositionParameters) {
 
 fn _remove_account_position(account: &Subaccount) {
     ACCOUNTS_POSITION.with_borrow_mut(|ref_user_position| ref_user_position.remove(account));
+    _remove_position_triggers(account);
+    _cancel_reduce_only_orders(account);
+}
+
+/// Cancel Reduce Only Orders
+///
+/// Removes the unfilled reduce only orders of an account from the book and settles the filled or partially filled ones
+/// ,utilised once the account's position is closed
+///
+/// Note
+///  - As the position is closed ,the value of each fill is paid out to the owner (see Execute Reduce Only Order)
+fn _cancel_reduce_only_orders(account: &Subaccount) {
+    for account_order in _get_reduce_only_orders(account) {
+        let unfilled = TICKS_DETAILS
+            .with_borrow(|ticks_details| _is_order_unfilled(&account_order.order, ticks_details));
+
+        // an order whose amounts can not be calculated is left on the book
+        if unfilled {
+            if _close_order(&account_order.order).is_ok() {
+                _remove_account_limit_order(account, &account_order);
+            }
+        } else {
+            let _ = _execute_reduce_only_order(*account, account_order);
+        }
+    }
+}
+
+/// Settle Reduce Only Orders
+///
+/// Settles the filled or partially filled reduce only orders of an account against the account's position
+///
+/// Note
+///  - Utilised before a position is closed fully ,so the close only closes the share not already closed by the fills
+fn _settle_reduce_only_orders(account: &Subaccount) {
+    for account_order in _get_reduce_only_orders(account) {
+        // a settlement that closes the position settles or cancels the remaining orders
+        if _get_account_limit_order(account, account_order.order_id).is_none() {
+            continue;
+        }
+
+        let unfilled = TICKS_DETAILS
+            .with_borrow(|ticks_details| _is_order_unfilled(&account_order.order, ticks_details));
+
+        // an order whose amounts can not be calculated is left on the book
+        if !unfilled {
+            let _ = _execute_reduce_only_order(*account, account_order);
+        }
+    }
+}
+
+/// Get Reduce Only Orders
+///
+/// Returns the reduce only orders of an account
+fn _get_reduce_only_orders(account: &Subaccount) -> Vec<AccountLimitOrder> {
+    ACCOUNTS_LIMIT_ORDERS.with_borrow(|reference| {
+        reference
+            .range((*account, 0)..=(*account, OrderId::MAX))
+            .filter(|(_, account_order)| account_order.reduce_only)
+            .map(|(_, account_order)| account_order)
+            .collect()
+    })
+}
+
+/// Set Position Triggers
+///
+/// Stores the triggers of a position and starts the triggers check ,removes them if no trigger is set
+fn _set_position_triggers(account: Subaccount, triggers: PositionTriggers) {
+    if triggers._is_empty() {
+        _remove_position_triggers(&account);
+        return;
+    }
+    POSITIONS_TRIGGERS.with_borrow_mut(|reference| reference.insert(account, triggers));
+    _schedule_position_triggers_check();
+}
+
+fn _insert_account_limit_order(account: Subaccount, account_order: AccountLimitOrder) {
+    ACCOUNTS_LIMIT_ORDERS.with_borrow_mut(|reference| {
+        reference.insert((account, account_order.order_id), account_order)
+    });
+    TICKS_LIMIT_ORDERS.with_borrow_mut(|reference| {
+        reference.insert(
+            (
+                account_order.order.ref_tick,
+                account,
+                account_order.order_id,
+            ),
+            (),
+        )
+    });
+}
+
+fn _remove_account_limit_order(account: &Subaccount, account_order: &AccountLimitOrder) {
+    ACCOUNTS_LIMIT_ORDERS
+        .with_borrow_mut(|reference| reference.remove(&(*account, account_order.order_id)));
+    TICKS_LIMIT_ORDERS.with_borrow_mut(|reference| {
+        reference.remove(&(
+            account_order.order.ref_tick,
+            *account,
+            account_order.order_id,
+        ))
+    });
+    EXECUTABLE_ACCOUNTS_LIMIT_ORDERS
+        .with_borrow_mut(|reference| reference.remove(&(*account, account_order.order_id)));
+}
+
+/// Next Limit Order ID
+///
+/// Returns a new unique order id
+fn _next_limit_order_id() -> OrderId {
+    LIMIT_ORDER_ID.with_borrow_mut(|reference| {
+        let order_id = *reference.get() + 1;
+        reference.set(order_id).unwrap();
+        order_id
+    })
+}
+
+fn _remove_position_triggers(account: &Subaccount) {
+    POSITIONS_TRIGGERS.with_borrow_mut(|reference| reference.remove(account));
 }
 
 fn _insert_account_error_log(account: Subaccount, error_log: PositionUpdateErrorLog) {
@@ -1711,6 +4860,18 @@ fn _set_pending_timer(timer_id: TimerId) {
         *reference = timer_id;
     })
 }
+
+fn _set_triggers_timer(timer_id: TimerId) {
+    TRIGGERS_TIMER.with_borrow_mut(|reference| {
+        *reference = timer_id;
+    })
+}
+
+fn _set_expiry_timer(timer_id: TimerId) {
+    EXPIRY_TIMER.with_borrow_mut(|reference| {
+        *reference = timer_id;
+    })
+}
 ////////////////////////////////////////////////////////////////////////////////////////////////////
 ///////////////////////////////////////////////////////////////////////////////////////////////////
 ////////////////////////////////////////////////////////////////////////////////////////////////////
@@ -1784,16 +4945,62 @@ struct PositionParameters {
     ///
     /// Note: For order type, position this  is time  order was excuted
     timestamp: Time,
+    /// Extra Margin
+    ///
+    /// collateral added above the position's debt ,it is not part of the position volume
+    /// and is paid back to the owner when the position is fully closed
+    extra_margin: Amount,
+    /// Account Index
+    ///
+    /// the index of the owner's account holding the position
+    account_index: u8,
 }
 
 impl Storable for PositionParameters {
     const BOUND: Bound = Bound::Bounded {
-        max_size: 180,
+        max_size: 220,
         is_fixed_size: false,
     };
+    /// Note
+    ///  - Fields are only ever appended to PositionParameters ,positions written by an older layout decode with
+    ///    the appended fields set to their defaults
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        // Direct binary deserialization using bincode for better performance
-        bincode::deserialize(bytes.as_ref()).expect("Failed to deserialize TickDetails")
+        #[derive(Deserialize)]
+        struct BaseLayout {
+            owner: Principal,
+            entry_tick: Tick,
+            long: bool,
+            collateral_value: Amount,
+            debt_value: Amount,
+            volume_share: Amount,
+            interest_rate: u32,
+            order_type: PositionOrderType,
+            timestamp: Time,
+        }
+
+        let mut reader = bytes.as_ref();
+
+        let base: BaseLayout = bincode::deserialize_from(&mut reader)
+            .expect("Failed to deserialize PositionParameters");
+
+        let mut position = PositionParameters {
+            owner: base.owner,
+            entry_tick: base.entry_tick,
+            long: base.long,
+            collateral_value: base.collateral_value,
+            debt_value: base.debt_value,
+            volume_share: base.volume_share,
+            interest_rate: base.interest_rate,
+            order_type: base.order_type,
+            timestamp: base.timestamp,
+            extra_margin: 0,
+            account_index: 0,
+        };
+
+        let _ = _read_appended_field(&mut reader, &mut position.extra_margin)
+            && _read_appended_field(&mut reader, &mut position.account_index);
+
+        position
     }
 
     fn to_bytes(&self) -> Cow<[u8]> {
@@ -1803,12 +5010,170 @@ impl Storable for PositionParameters {
     }
 }
 
+/// Account Limit Order
+///
+/// A working limit order of an account ,once filled the order is added to the account's market position
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, Copy)]
+struct AccountLimitOrder {
+    /// The owner of the account
+    owner: Principal,
+    /// The index of the account ,utilised for settling with the vault when the order is executed
+    account_index: u8,
+    /// The id of the order
+    order_id: OrderId,
+    /// true if order is a buy order
+    long: bool,
+    /// The collateral value of the order
+    collateral_value: Amount,
+    /// The debt value of the order
+    debt_value: Amount,
+    /// The interest rate on the debt value of the order
+    interest_rate: u32,
+    /// The limit order placed at the reference tick
+    order: LimitOrder,
+    /// timestamp when the order was placed
+    timestamp: Time,
+    /// true if the order only closes the account's position
+    reduce_only: bool,
+    /// The share of the position's volume share closed when a reduce only order is fully filled
+    close_share: Amount,
+}
+
+impl Storable for AccountLimitOrder {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 200,
+        is_fixed_size: false,
+    };
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        bincode::deserialize(bytes.as_ref()).expect("Failed to deserialize AccountLimitOrder")
+    }
+
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let serialized = bincode::serialize(self).expect("Failed to serialize AccountLimitOrder");
+        Cow::Owned(serialized)
+    }
+}
+
 #[derive(CandidType)]
 enum PositionStatus {
     FILLED,
     UNFILLED,
     PARTIAL,
 }
+
+/// Funding Settlement
+///
+/// A funding rate settlement between the long and short market directions
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, Copy)]
+struct FundingSettlement {
+    /// timestamp when the funding rate was settled
+    timestamp: Time,
+    /// The perp price ,scaled by the exchange rate decimals
+    perp_price: u128,
+    /// The spot price from the exchange rate canister ,scaled by the exchange rate decimals
+    spot_price: u128,
+    /// The funding rate in percentage ,positive if longs paid shorts and negative otherwise
+    funding_rate: i64,
+    /// The net volume of longs before settlement
+    net_volume_long_before: Amount,
+    /// The net volume of shorts before settlement
+    net_volume_short_before: Amount,
+    /// The net volume of longs after settlement
+    net_volume_long_after: Amount,
+    /// The net volume of shorts after settlement
+    net_volume_short_after: Amount,
+}
+
+impl Storable for FundingSettlement {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 140,
+        is_fixed_size: false,
+    };
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        bincode::deserialize(bytes.as_ref()).expect("Failed to deserialize FundingSettlement")
+    }
+
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let serialized = bincode::serialize(self).expect("Failed to serialize FundingSettlement");
+        Cow::Owned(serialized)
+    }
+}
+
+/// Execution Flag
+///
+/// The time in force of an order
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq)]
+enum ExecutionFlag {
+    /// the order is rejected if it would be executed immediately
+    PostOnly,
+    /// the order is executed immediately as far as possible and the unfilled amount is refunded
+    ImmediateOrCancel,
+    /// the order is rejected unless it can be executed immediately in full
+    FillOrKill,
+}
+
+/// Liquidation Candidate
+///
+/// A position returned by the liquidation scan
+#[derive(CandidType, Deserialize, Clone, Copy)]
+struct LiquidationCandidate {
+    /// The owner of the position
+    owner: Principal,
+    /// The account index of the position
+    account_index: u8,
+    /// The position
+    position: PositionParameters,
+    /// The current collateral value as a percentage of the current position value
+    margin_ratio: i64,
+    /// true if the position can be liquidated now
+    liquidatable: bool,
+}
+
+/// Ic Clock
+///
+/// Clock for the corelib functions ,returns the current canister time
+struct IcClock;
+
+impl Clock for IcClock {
+    fn now(&self) -> Time {
+        ic_cdk::api::time()
+    }
+}
+
+/// Market Order Quote
+///
+/// The expected result of a market order as returned by the quote market order query
+#[derive(CandidType, Deserialize, Clone)]
+struct MarketOrderQuote {
+    /// The amount of base asset that would be bought or sold
+    base_amount: Amount,
+    /// The amount of quote asset that would be spent or received
+    quote_amount: Amount,
+    /// The average execution price expressed as a tick
+    average_price: Tick,
+    /// The tick the order would end at
+    resulting_tick: Tick,
+    /// The ticks whose liquidity would be fully consumed
+    crossed_ticks: Vec<Tick>,
+    /// The collateral that would be sent back as the order can not be filled fully
+    unused_collateral: Amount,
+    /// The taker fee that would be charged
+    taker_fee: Amount,
+    /// The estimated tick at which the position would be liquidatable ,expressed as a tick
+    liquidation_price: Tick,
+}
+
+/// Close Amount
+///
+/// The amount of a position to close when closing partially
+#[derive(CandidType, Deserialize, Clone, Copy)]
+enum CloseAmount {
+    /// fraction of the position in percentage ,i.e 50 * _ONE_PERCENT closes half the position
+    Fraction(u64),
+    /// value of the position to close in the quote asset
+    Value(Amount),
+}
+
 /// ManageDebtParams is utilised to handle debt handling and  repayment
 #[derive(Copy, Clone, Default, Deserialize, CandidType)]
 struct ManageDebtParams {
@@ -1939,6 +5304,24 @@ impl Vault {
         }
     }
 
+    /// Credit Trading Fees
+    ///
+    /// Credits collected trading fees to the recipient's balance
+    ///
+    /// Returns
+    ///  - Credited :true if the vault credited the fees and false otherwise
+    ///
+    /// Note :The call waits unboundedly so a failed call is known to not have credited the fees
+    pub async fn credit_trading_fees(&self, recipient: Principal, amount: Amount) -> bool {
+        let call = Call::unbounded_wait(self.canister_id, "managePositionUpdate").with_args(&(
+            recipient,
+            amount,
+            ManageDebtParams::default(),
+        ));
+
+        return call.await.is_ok();
+    }
+
     /// Create Position Validity Check
     ///
     /// Checks if position can be opened by checking that uswer has sufficient balance and amount to use as debt is available as free liquidity
@@ -1988,5 +5371,170 @@ export_candid!();
 pub mod corelib;
 pub mod types;
 
+mod triggers;
+
+mod history;
+
+mod insurance_fund;
+
+mod legacy_state;
+
+mod expiry;
+
 #[cfg(test)]
 pub mod closed_integration_tests;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use corelib::calc_lib::MathError;
+
+    fn _position() -> PositionParameters {
+        PositionParameters {
+            owner: Principal::anonymous(),
+            long: true,
+            entry_tick: 20_000_000,
+            collateral_value: 500,
+            debt_value: 1_000,
+            interest_rate: 0,
+            volume_share: 100,
+            order_type: PositionOrderType::Market,
+            timestamp: 0,
+            extra_margin: 0,
+            account_index: 0,
+        }
+    }
+
+    // a tenth of the debt is accrued as interest
+    fn _accrued_interest(debt: Amount) -> Amount {
+        debt / 10
+    }
+
+    #[test]
+    fn test_repay_debt_slice() {
+        let mut position = _position();
+
+        let (profit, manage_debt_params) =
+            _repay_debt_slice(&mut position, 50, 700, _accrued_interest, 50);
+
+        assert_eq!(profit, 150);
+        assert_eq!(manage_debt_params.initial_debt, 500);
+        assert_eq!(manage_debt_params.net_debt, 550);
+        assert_eq!(manage_debt_params.amount_repaid, 550);
+
+        assert_eq!(position.debt_value, 500);
+        assert_eq!(position.collateral_value, 250);
+        assert_eq!(position.timestamp, 0);
+    }
+
+    #[test]
+    fn test_repay_debt_slice_with_shortfall() {
+        let mut position = _position();
+
+        let (profit, manage_debt_params) =
+            _repay_debt_slice(&mut position, 50, 300, _accrued_interest, 50);
+
+        // the interest on the whole debt is accrued and the shortfall stays as debt
+        assert_eq!(profit, 0);
+        assert_eq!(manage_debt_params.initial_debt, 1_000);
+        assert_eq!(manage_debt_params.net_debt, 1_100);
+        assert_eq!(manage_debt_params.amount_repaid, 300);
+
+        assert_eq!(position.debt_value, 800);
+        assert_eq!(position.collateral_value, 250);
+        // the interest period restarts at the close
+        assert_eq!(position.timestamp, 50);
+    }
+
+    fn _set_fee_rates(maker_fee_rate: u64, taker_fee_rate: u64, insurance_fund_share: u64) {
+        _set_state_details(StateDetails {
+            maker_fee_rate,
+            taker_fee_rate,
+            insurance_fund_share,
+            ..Default::default()
+        });
+    }
+
+    fn _accrued_fees() -> Amount {
+        ACCRUED_FEES.with_borrow(|reference| *reference.get())
+    }
+
+    #[test]
+    fn test_charge_trading_fee() {
+        // 0.1% maker fee ,0.5% taker fee and a fifth of every fee to the insurance fund
+        _set_fee_rates(_ONE_PERCENT / 10, _ONE_PERCENT / 2, 20 * _ONE_PERCENT);
+
+        let maker_fee = _charge_trading_fee(1_000_000, true, 1_000_000);
+        assert_eq!(maker_fee, 1_000);
+
+        let taker_fee = _charge_trading_fee(1_000_000, false, 1_000_000);
+        assert_eq!(taker_fee, 5_000);
+
+        assert_eq!(_get_insurance_fund_balance(), 1_200);
+        assert_eq!(_accrued_fees(), 4_800);
+    }
+
+    #[test]
+    fn test_trading_fee_is_capped_at_max_fee() {
+        _set_fee_rates(_ONE_PERCENT, _ONE_PERCENT, 20 * _ONE_PERCENT);
+
+        // the fee can not exceed the collateral or profit it is taken from
+        assert_eq!(_charge_trading_fee(1_000_000, false, 3_000), 3_000);
+        assert_eq!(_charge_trading_fee(1_000_000, true, 0), 0);
+
+        assert_eq!(_get_insurance_fund_balance(), 600);
+        assert_eq!(_accrued_fees(), 2_400);
+    }
+
+    #[test]
+    fn test_insurance_fund_share_is_capped_at_fee() {
+        // a share over 100% still only moves the fee into the insurance fund
+        _set_fee_rates(0, _ONE_PERCENT, 200 * _ONE_PERCENT);
+
+        assert_eq!(_charge_trading_fee(1_000_000, false, 1_000_000), 10_000);
+
+        assert_eq!(_get_insurance_fund_balance(), 10_000);
+        assert_eq!(_accrued_fees(), 0);
+    }
+
+    #[test]
+    fn test_add_volume_without_net_volume() {
+        // every share of the direction is left without any volume
+        let mut funding_rate_tracker = FundingRateTracker {
+            net_volume_long: 0,
+            total_long_shares: 1_000,
+            net_volume_short: 0,
+            total_short_shares: 0,
+        };
+
+        assert!(matches!(
+            funding_rate_tracker.add_volume(500, true),
+            Err(MathError::DivisionByZero)
+        ));
+        assert_eq!(funding_rate_tracker.total_long_shares, 1_000);
+        assert_eq!(funding_rate_tracker.net_volume_long, 0);
+
+        // the first volume of a direction mints shares one to one
+        assert!(matches!(
+            funding_rate_tracker.add_volume(500, false),
+            Ok(500)
+        ));
+    }
+
+    #[test]
+    fn test_remove_volume_beyond_total_shares() {
+        let mut funding_rate_tracker = FundingRateTracker {
+            net_volume_long: 1_000,
+            total_long_shares: 1_000,
+            net_volume_short: 0,
+            total_short_shares: 0,
+        };
+
+        assert!(matches!(
+            funding_rate_tracker.remove_volume(1_500, true),
+            Err(MathError::Underflow)
+        ));
+        assert_eq!(funding_rate_tracker.total_long_shares, 1_000);
+        assert_eq!(funding_rate_tracker.net_volume_long, 1_000);
+    }
+}
@@ -0,0 +1,296 @@
+//! # Position Trigger Functions
+//!
+//! These functions close market positions automatically once the market crosses their stop loss or take profit tick
+//!
+//! Operation
+//!   - the triggers of each position are stored in POSITIONS TRIGGERS under the position's account
+//!   - while any trigger is set ,a timer checks every trigger against the best offer on the closing side of the book
+//!     (highest buy offer for a long and lowest sell offer for a short) and closes each triggered position at the default slippage
+//!   - a position that can only be partially closed keeps its triggers and is retried on the next check
+
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+use ic_cdk_timers::TimerId;
+
+use crate::corelib::tick_lib::_compressed_tick;
+use crate::{
+    _close_market_position, _get_account_position, _get_highest_buy_offer_tick,
+    _get_lowest_sell_offer_tick, _get_market_details, _get_triggers_timer,
+    _remove_position_triggers, _set_triggers_timer, PositionOrderType, Vault,
+    _MAX_TRIGGERED_POSITIONS_PER_CHECK, _POSITION_TRIGGERS_CHECK_INTERVAL, POSITIONS_TRIGGERS,
+};
+
+use std::borrow::Cow;
+use std::time::Duration;
+
+use ic_stable_structures::{storable::Bound, Storable};
+
+type Tick = u64;
+type Subaccount = [u8; 32];
+
+/// Schedule Position Triggers Check
+///
+/// Starts the triggers check timer if any market position has a trigger set and the timer is not already running
+///
+/// Note
+///  - triggers of limit positions are only checked once the position is converted to a market position
+pub(crate) fn _schedule_position_triggers_check() {
+    if _has_checkable_triggers() && _get_triggers_timer() == TimerId::default() {
+        let timer_id = ic_cdk_timers::set_timer_interval(
+            Duration::from_nanos(_POSITION_TRIGGERS_CHECK_INTERVAL),
+            || {
+                _execute_position_triggers();
+            },
+        );
+
+        _set_triggers_timer(timer_id);
+    }
+}
+
+/// Execute Position Triggers
+///
+/// Closes every market position whose stop loss or take profit has been crossed ,at most _MAX_TRIGGERED_POSITIONS_PER_CHECK per call
+fn _execute_position_triggers() {
+    let positions_triggers: Vec<(Subaccount, PositionTriggers)> =
+        POSITIONS_TRIGGERS.with_borrow(|reference| reference.iter().collect());
+
+    if !_has_checkable_triggers() {
+        ic_cdk_timers::clear_timer(_get_triggers_timer());
+        _set_triggers_timer(TimerId::default());
+        return;
+    }
+
+    let market_details = _get_market_details();
+
+    let vault = Vault::init(market_details.vault_id);
+
+    let mut executed = 0;
+
+    for (account, triggers) in positions_triggers {
+        if executed == _MAX_TRIGGERED_POSITIONS_PER_CHECK {
+            break;
+        }
+
+        let Some(mut position) = _get_account_position(&account) else {
+            _remove_position_triggers(&account);
+            continue;
+        };
+
+        // limit positions are only checked after being filled
+        if let PositionOrderType::Limit(_) = position.order_type {
+            continue;
+        }
+
+        let closing_tick = if position.long {
+            _get_highest_buy_offer_tick()
+        } else {
+            _get_lowest_sell_offer_tick()
+        };
+
+        if !triggers._triggered(position.long, closing_tick) {
+            continue;
+        }
+
+        if _close_market_position(
+            triggers.owner,
+            triggers.account_index,
+            &mut position,
+            None,
+            vault,
+        )
+        .is_err()
+        {
+            continue;
+        }
+
+        executed += 1;
+    }
+}
+
+/// Has Checkable Triggers
+///
+/// Returns true if any trigger is set for a market position or for an account whose position no longer exists
+/// (so that the stale trigger is removed on the next check)
+fn _has_checkable_triggers() -> bool {
+    POSITIONS_TRIGGERS.with_borrow(|reference| {
+        reference
+            .iter()
+            .any(|(account, _)| match _get_account_position(&account) {
+                Some(position) => matches!(position.order_type, PositionOrderType::Market),
+                None => true,
+            })
+    })
+}
+
+/// Position Triggers
+///
+/// The stop loss and take profit ticks attached to a position
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, Copy)]
+pub(crate) struct PositionTriggers {
+    /// The owner of the position
+    owner: Principal,
+    /// The account index of the position ,utilised for settling with the vault when the position is closed by a trigger
+    account_index: u8,
+    /// Stop Loss Tick
+    ///
+    /// the position is closed when the market moves against it up to this tick
+    pub(crate) stop_loss_tick: Option<Tick>,
+    /// Take Profit Tick
+    ///
+    /// the position is closed when the market moves in its favour up to this tick
+    pub(crate) take_profit_tick: Option<Tick>,
+}
+
+impl PositionTriggers {
+    pub(crate) fn init(
+        owner: Principal,
+        account_index: u8,
+        stop_loss_tick: Option<Tick>,
+        take_profit_tick: Option<Tick>,
+        tick_spacing: u64,
+    ) -> Self {
+        PositionTriggers {
+            owner,
+            account_index,
+            stop_loss_tick: stop_loss_tick.map(|tick| _compressed_tick(tick, tick_spacing)),
+            take_profit_tick: take_profit_tick.map(|tick| _compressed_tick(tick, tick_spacing)),
+        }
+    }
+
+    pub(crate) fn _is_empty(&self) -> bool {
+        self.stop_loss_tick.is_none() && self.take_profit_tick.is_none()
+    }
+
+    /// Valid For
+    ///
+    /// checks that the stop loss is on the losing side and the take profit on the winning side of the reference tick
+    pub(crate) fn _valid_for(&self, long: bool, reference_tick: Tick) -> bool {
+        let valid_stop_loss = self.stop_loss_tick.map_or(true, |tick| {
+            if long {
+                tick < reference_tick
+            } else {
+                tick > reference_tick
+            }
+        });
+        let valid_take_profit = self.take_profit_tick.map_or(true, |tick| {
+            if long {
+                tick > reference_tick
+            } else {
+                tick < reference_tick
+            }
+        });
+        return valid_stop_loss && valid_take_profit;
+    }
+
+    /// Triggered
+    ///
+    /// checks if either trigger has been crossed by the current closing tick
+    ///
+    /// Note: a closing tick of zero means there is no liquidity to close against
+    fn _triggered(&self, long: bool, closing_tick: Tick) -> bool {
+        if closing_tick == 0 {
+            return false;
+        }
+        let stop_loss_hit = self.stop_loss_tick.is_some_and(|tick| {
+            if long {
+                closing_tick <= tick
+            } else {
+                closing_tick >= tick
+            }
+        });
+        let take_profit_hit = self.take_profit_tick.is_some_and(|tick| {
+            if long {
+                closing_tick >= tick
+            } else {
+                closing_tick <= tick
+            }
+        });
+        return stop_loss_hit || take_profit_hit;
+    }
+}
+
+impl Storable for PositionTriggers {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 80,
+        is_fixed_size: false,
+    };
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        bincode::deserialize(bytes.as_ref()).expect("Failed to deserialize PositionTriggers")
+    }
+
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let serialized = bincode::serialize(self).expect("Failed to serialize PositionTriggers");
+        Cow::Owned(serialized)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn _triggers(stop_loss_tick: Option<Tick>, take_profit_tick: Option<Tick>) -> PositionTriggers {
+        PositionTriggers::init(
+            Principal::anonymous(),
+            0,
+            stop_loss_tick,
+            take_profit_tick,
+            1,
+        )
+    }
+
+    #[test]
+    fn test_ticks_are_compressed() {
+        let triggers =
+            PositionTriggers::init(Principal::anonymous(), 0, Some(199_000), Some(201_000), 100);
+
+        assert_eq!(triggers.stop_loss_tick, Some(1_990));
+        assert_eq!(triggers.take_profit_tick, Some(2_010));
+    }
+
+    #[test]
+    fn test_valid_for_long() {
+        assert!(_triggers(Some(190), Some(210))._valid_for(true, 200));
+        assert!(_triggers(None, None)._valid_for(true, 200));
+
+        assert!(!_triggers(Some(200), None)._valid_for(true, 200));
+        assert!(!_triggers(Some(210), None)._valid_for(true, 200));
+        assert!(!_triggers(None, Some(200))._valid_for(true, 200));
+        assert!(!_triggers(None, Some(190))._valid_for(true, 200));
+    }
+
+    #[test]
+    fn test_valid_for_short() {
+        assert!(_triggers(Some(210), Some(190))._valid_for(false, 200));
+
+        assert!(!_triggers(Some(190), None)._valid_for(false, 200));
+        assert!(!_triggers(None, Some(210))._valid_for(false, 200));
+    }
+
+    #[test]
+    fn test_triggered_long() {
+        let triggers = _triggers(Some(190), Some(210));
+
+        assert!(!triggers._triggered(true, 200));
+        assert!(triggers._triggered(true, 190));
+        assert!(triggers._triggered(true, 180));
+        assert!(triggers._triggered(true, 210));
+        assert!(triggers._triggered(true, 220));
+    }
+
+    #[test]
+    fn test_triggered_short() {
+        let triggers = _triggers(Some(210), Some(190));
+
+        assert!(!triggers._triggered(false, 200));
+        assert!(triggers._triggered(false, 210));
+        assert!(triggers._triggered(false, 190));
+        assert!(!_triggers(Some(210), None)._triggered(false, 190));
+    }
+
+    #[test]
+    fn test_no_liquidity_never_triggers() {
+        assert!(!_triggers(Some(190), Some(210))._triggered(true, 0));
+        assert!(!_triggers(Some(190), Some(210))._triggered(false, 0));
+    }
+}
@@ -1,7 +1,8 @@
-use crate::corelib::calc_lib::{_calc_shares, _calc_shares_value, _percentage};
+use crate::corelib::calc_lib::{_calc_shares, _calc_shares_value, _checked_percentage, MathError};
 use bincode;
 use candid::{CandidType, Principal};
 use ic_stable_structures::{storable::Bound, Storable};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 pub type Tick = u64;
@@ -143,43 +144,98 @@ pub struct FundingRateTracker {
 }
 
 impl FundingRateTracker {
-    pub fn add_volume(&mut self, delta: Amount, long: bool) -> Amount {
-        if long {
-            let volume_share = _calc_shares(delta, self.total_long_shares, self.net_volume_long);
-            self.total_long_shares += volume_share;
-            self.net_volume_long += delta;
-            return volume_share;
+    /// Add Volume
+    ///
+    /// Adds volume to a market direction and returns the volume share minted for it
+    ///
+    /// Note
+    ///  - The tracker is left unchanged on an error
+    pub fn add_volume(&mut self, delta: Amount, long: bool) -> Result<Amount, MathError> {
+        let (net_volume, total_shares) = if long {
+            (self.net_volume_long, self.total_long_shares)
         } else {
-            let volume_share = _calc_shares(delta, self.total_short_shares, self.net_volume_short);
-            self.total_short_shares += volume_share;
-            self.net_volume_short += delta;
-            return volume_share;
-        }
+            (self.net_volume_short, self.total_short_shares)
+        };
+
+        let volume_share = _calc_shares(delta, total_shares, net_volume)?;
+
+        let total_shares = total_shares
+            .checked_add(volume_share)
+            .ok_or(MathError::Overflow)?;
+        let net_volume = net_volume.checked_add(delta).ok_or(MathError::Overflow)?;
+
+        self._set_direction(long, net_volume, total_shares);
+        return Ok(volume_share);
     }
 
-    pub fn remove_volume(&mut self, delta: Amount, long: bool) -> Amount {
-        if long {
-            let value = _calc_shares_value(delta, self.total_long_shares, self.net_volume_long);
-            self.net_volume_long -= value;
-            self.total_long_shares -= delta;
-            return value;
+    /// Remove Volume
+    ///
+    /// Burns a volume share of a market direction and returns the volume removed for it
+    ///
+    /// Note
+    ///  - The tracker is left unchanged on an error
+    pub fn remove_volume(&mut self, delta: Amount, long: bool) -> Result<Amount, MathError> {
+        let (net_volume, total_shares) = if long {
+            (self.net_volume_long, self.total_long_shares)
         } else {
-            let value = _calc_shares_value(delta, self.total_short_shares, self.net_volume_short);
-            self.net_volume_short -= value;
-            self.total_short_shares -= delta;
-            return value;
-        }
+            (self.net_volume_short, self.total_short_shares)
+        };
+
+        let value = _calc_shares_value(delta, total_shares, net_volume)?;
+
+        let total_shares = total_shares
+            .checked_sub(delta)
+            .ok_or(MathError::Underflow)?;
+        let net_volume = net_volume.checked_sub(value).ok_or(MathError::Underflow)?;
+
+        self._set_direction(long, net_volume, total_shares);
+        return Ok(value);
     }
 
-    pub fn settle_funding_rate(&mut self, funding_rate: u64, positive: bool) {
+    /// Settle Funding Rate
+    ///
+    /// Moves the funding rate percentage of the paying direction's volume to the other direction ,
+    /// longs pay shorts if positive and shorts pay longs otherwise
+    ///
+    /// Note
+    ///  - The tracker is left unchanged on an error
+    pub fn settle_funding_rate(
+        &mut self,
+        funding_rate: u64,
+        positive: bool,
+    ) -> Result<(), MathError> {
+        let (paying_volume, receiving_volume) = if positive {
+            (self.net_volume_long, self.net_volume_short)
+        } else {
+            (self.net_volume_short, self.net_volume_long)
+        };
+
+        let amount_to_settle = _checked_percentage(funding_rate, paying_volume)?;
+
+        let paying_volume = paying_volume
+            .checked_sub(amount_to_settle)
+            .ok_or(MathError::Underflow)?;
+        let receiving_volume = receiving_volume
+            .checked_add(amount_to_settle)
+            .ok_or(MathError::Overflow)?;
+
         if positive {
-            let amount_to_settle = _percentage(funding_rate, self.net_volume_long);
-            self.net_volume_short += amount_to_settle;
-            self.net_volume_long -= amount_to_settle;
+            self.net_volume_long = paying_volume;
+            self.net_volume_short = receiving_volume;
+        } else {
+            self.net_volume_short = paying_volume;
+            self.net_volume_long = receiving_volume;
+        }
+        return Ok(());
+    }
+
+    fn _set_direction(&mut self, long: bool, net_volume: Amount, total_shares: Amount) {
+        if long {
+            self.net_volume_long = net_volume;
+            self.total_long_shares = total_shares;
         } else {
-            let amount_to_settle = _percentage(funding_rate, self.net_volume_short);
-            self.net_volume_long += amount_to_settle;
-            self.net_volume_short -= amount_to_settle
+            self.net_volume_short = net_volume;
+            self.total_short_shares = total_shares;
         }
     }
 }
@@ -272,12 +328,95 @@ pub struct StateDetails {
     ///
     /// -this amount  is actuallly  reduced by the reduction i.e (10::pow(token_decimal - 6))
     pub min_collateral: Amount,
+    /// Liquidation Fee Rate
+    ///
+    /// the percentage of the remaining collateral of a liquidated position paid to the liquidator
+    ///
+    /// Note:
+    ///
+    /// -this is in percentage i.e 1% is written as _ONE_PERCENT
+    pub liquidation_fee_rate: u64,
+    /// Insurance Fund Share
+    ///
+    /// the percentage of fees and liquidation penalties added to the insurance fund
+    ///
+    /// Note:
+    ///
+    /// -this is in percentage i.e 1% is written as _ONE_PERCENT
+    pub insurance_fund_share: u64,
+    /// Funding Interval
+    ///
+    /// the time between funding rate settlements in nanoseconds
+    pub funding_interval: Time,
+    /// Max Funding Rate
+    ///
+    /// the maximum absolute funding rate applied in a single settlement
+    ///
+    /// Note:
+    ///
+    /// -this is in percentage i.e 1% is written as _ONE_PERCENT
+    pub max_funding_rate: u64,
+    /// Funding Interest Rate
+    ///
+    /// the baseline interest component added to the premium on every settlement ,positive if longs pay shorts
+    ///
+    /// Note:
+    ///
+    /// -this is in percentage i.e 1% is written as _ONE_PERCENT
+    pub funding_interest_rate: i64,
+    /// Funding Damping Factor
+    ///
+    /// the percentage of the premium applied in a single settlement
+    ///
+    /// Note:
+    ///
+    /// -this is in percentage i.e 100% is written as 100 * _ONE_PERCENT
+    pub funding_damping_factor: u64,
+    /// Maker Fee Rate
+    ///
+    /// the fee charged on the filled value of a limit order
+    ///
+    /// Note:
+    ///
+    /// -this is in percentage i.e 1% is written as _ONE_PERCENT
+    pub maker_fee_rate: u64,
+    /// Taker Fee Rate
+    ///
+    /// the fee charged on the quote volume of a swap
+    ///
+    /// Note:
+    ///
+    /// -this is in percentage i.e 1% is written as _ONE_PERCENT
+    pub taker_fee_rate: u64,
 }
 
 impl Storable for StateDetails {
     const BOUND: Bound = Bound::Unbounded;
+    /// Note
+    ///  - Fields are only ever appended to StateDetails ,state details written by an older layout decode with
+    ///    the appended fields set to their defaults
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        bincode::deserialize(bytes.as_ref()).expect("Failed to deserialize StateDetails")
+        let mut reader = bytes.as_ref();
+        let mut state_details = StateDetails::default();
+
+        let read_all = _read_appended_field(&mut reader, &mut state_details.not_paused)
+            && _read_appended_field(&mut reader, &mut state_details.max_leveragex10)
+            && _read_appended_field(&mut reader, &mut state_details.min_collateral);
+
+        if !read_all {
+            panic!("Failed to deserialize StateDetails");
+        }
+
+        let _ = _read_appended_field(&mut reader, &mut state_details.liquidation_fee_rate)
+            && _read_appended_field(&mut reader, &mut state_details.insurance_fund_share)
+            && _read_appended_field(&mut reader, &mut state_details.funding_interval)
+            && _read_appended_field(&mut reader, &mut state_details.max_funding_rate)
+            && _read_appended_field(&mut reader, &mut state_details.funding_interest_rate)
+            && _read_appended_field(&mut reader, &mut state_details.funding_damping_factor)
+            && _read_appended_field(&mut reader, &mut state_details.maker_fee_rate)
+            && _read_appended_field(&mut reader, &mut state_details.taker_fee_rate);
+
+        state_details
     }
 
     fn to_bytes(&self) -> Cow<[u8]> {
@@ -287,6 +426,25 @@ impl Storable for StateDetails {
     }
 }
 
+/// Read Appended Field
+///
+/// Reads the next bincode encoded field of a struct into field
+///
+/// Returns
+///  - Read :false if the bytes end before the field ,the field is left unchanged
+pub fn _read_appended_field<T: DeserializeOwned>(reader: &mut &[u8], field: &mut T) -> bool {
+    if reader.is_empty() {
+        return false;
+    }
+    match bincode::deserialize_from(&mut *reader) {
+        Ok(value) => {
+            *field = value;
+            true
+        }
+        Err(_) => false,
+    }
+}
+
 #[derive(CandidType, Deserialize, Serialize, Clone, Copy)]
 pub enum TickState {
     BUY,
@@ -307,11 +465,11 @@ pub struct TickDetails {
 }
 
 impl TickDetails {
-    pub fn new() -> Self {
+    pub fn new(created_timestamp: Time) -> Self {
         TickDetails {
             tick_state: TickState::BUY,
             liq_bounds: LiquidityBoundary::default(),
-            created_timestamp: ic_cdk::api::time(),
+            created_timestamp,
         }
     }
     /// Add_liquidity function
@@ -412,3 +570,96 @@ impl LiquidityBoundary {
         self.lifetime_removed_liquidity += delta
     }
 }
+
+/// Book Level
+///
+/// A single price level of the order book as returned by the order book depth query
+#[derive(CandidType, Deserialize, Clone, Copy)]
+pub struct BookLevel {
+    /// The tick (price) of the level ,this is the bucket boundary when levels are grouped
+    pub tick: Tick,
+    /// The side of the book the level belongs to
+    pub side: TickState,
+    /// The static liquidity at the level
+    ///
+    /// Note: this is in the quote asset for buy offers and in the base asset for sell offers
+    pub liquidity: Amount,
+    /// The total liquidity from the best offer up to and including this level
+    pub cumulative_liquidity: Amount,
+}
+
+/// Order Book Depth
+///
+/// The buy and sell sides of the order book ,each ordered from the best offer outward
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct OrderBookDepth {
+    pub bids: Vec<BookLevel>,
+    pub asks: Vec<BookLevel>,
+}
+
+/// Market Error
+///
+/// The errors returned from the market's update functions
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarketError {
+    /// Market is paused
+    Paused,
+    /// Leverage is less than 1x (10)
+    InvalidLeverage,
+    /// Leverage is not less than the max leverage
+    LeverageTooHigh,
+    /// Collateral is less than the min collateral
+    CollateralTooSmall,
+    /// Vault does not have enough free liquidity for the debt or user does not have enough balance for the collateral
+    InsufficientVaultLiquidity,
+    /// User does not have enough balance in the vault
+    InsufficientBalance,
+    /// Account has no position
+    NoPosition,
+    /// Account already has a position
+    PositionExists,
+    /// Account has a failed position update that has to be retried first
+    PendingErrorLog,
+    /// Account has no failed position update
+    NoPendingErrorLog,
+    /// Limit order would be executed immediately at its tick
+    CrossesBook,
+    /// There is no liquidity to execute the order against
+    NoLiquidity,
+    /// Position is not a market position
+    NotMarketPosition,
+    /// Position is not a limit position
+    NotLimitPosition,
+    /// Stop loss or take profit is on the wrong side of the entry tick
+    InvalidTriggers,
+    /// Amount is zero or outside the allowed range
+    InvalidAmount,
+    /// Position is liquidatable
+    PositionLiquidatable,
+    /// Account has an open limit position or working limit orders that conflict with the request
+    LimitOrdersConflict,
+    /// Account has a position or working limit orders in the opposite direction
+    DirectionConflict,
+    /// Account has the max number of working limit orders
+    MaxOrdersReached,
+    /// Order does not exist
+    OrderNotFound,
+    /// Execution flag is not supported for the order type
+    InvalidExecutionFlag,
+    /// Fill or kill order can not be filled fully
+    NotFullyFilled,
+    /// Take profit tick is not above the entry tick of a long position or below that of a short position
+    InvalidTakeProfitTick,
+    /// Order has been partially or fully filled
+    OrderFilled,
+    /// Expiry time is not in the future
+    InvalidExpiry,
+    /// An amount calculation overflowed or divided by zero
+    CalculationError,
+}
+
+impl From<MathError> for MarketError {
+    fn from(_: MathError) -> Self {
+        MarketError::CalculationError
+    }
+}